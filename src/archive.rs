@@ -0,0 +1,687 @@
+//! The mbox file the old messages are appended to.
+//!
+//! Gzip is handled natively, the other compressions are done by piping through the external `xz`,
+//! `zstd` and `bzip2` programs (one process per archive, not per message).
+
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    self, BufWriter, Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write,
+};
+use std::ops::RangeInclusive;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{bail, ensure, Context, Error};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use log::warn;
+
+/// How the archive file is compressed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Names of all the compressions, as accepted on the command line.
+    pub const NAMES: &'static [&'static str] = &["none", "gzip", "xz", "zstd", "bzip2"];
+
+    /// Guesses the compression from the extension of the file.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("gz") => Compression::Gzip,
+            Some("xz") => Compression::Xz,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+
+    /// The compression levels the compression supports.
+    pub fn levels(self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip | Compression::Xz => Some(0..=9),
+            Compression::Zstd => Some(1..=19),
+            Compression::Bzip2 => Some(1..=9),
+        }
+    }
+
+    /// Checks the level (if any) makes sense for this compression.
+    pub fn check_level(self, level: Option<u32>) -> Result<(), Error> {
+        match (level, self.levels()) {
+            (None, _) => Ok(()),
+            (Some(_), None) => bail!("Compression level set without compression"),
+            (Some(level), Some(levels)) => {
+                ensure!(
+                    levels.contains(&level),
+                    "Compression level for {} must be in {}-{}",
+                    self,
+                    levels.start(),
+                    levels.end()
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Name of the external program doing the compression.
+    fn program_name(self) -> Option<&'static str> {
+        match self {
+            Compression::None | Compression::Gzip => None,
+            Compression::Xz => Some("xz"),
+            Compression::Zstd => Some("zstd"),
+            Compression::Bzip2 => Some("bzip2"),
+        }
+    }
+
+    /// The external program doing the compression.
+    fn program(self) -> Option<Command> {
+        let mut command = Command::new(self.program_name()?);
+        command.arg("-q");
+        Some(command)
+    }
+
+    /// Checks the external program the compression needs (if any) is installed.
+    ///
+    /// Only looks for an executable of the name in `PATH`, without running it.
+    pub fn check_program(self) -> Result<(), Error> {
+        let name = match self.program_name() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        ensure!(
+            on_path(name, env::var_os("PATH").as_deref()),
+            "The {} compression needs the {} program, which is not in PATH",
+            self,
+            name
+        );
+        Ok(())
+    }
+
+    /// Wraps the file to compress everything written into it.
+    fn encoder(self, file: File, level: Option<u32>) -> Result<Output, Error> {
+        if let Some(mut command) = self.program() {
+            command.arg("-c");
+            if let Some(level) = level {
+                command.arg(format!("-{}", level));
+            }
+            let stdout = file
+                .try_clone()
+                .context("Failed to duplicate the archive")?;
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(stdout)
+                .spawn()
+                .with_context(|| format!("Failed to run {}", self))?;
+            let input = child.stdin.take().expect("We asked for stdin");
+            return Ok(Output::Filter {
+                file,
+                child,
+                input: BufWriter::new(input),
+            });
+        }
+
+        let out = BufWriter::new(file);
+        match self {
+            Compression::Gzip => {
+                let level = level
+                    .map(flate2::Compression::new)
+                    .unwrap_or_else(flate2::Compression::best);
+                Ok(Output::Gzip(GzEncoder::new(out, level)))
+            }
+            _ => Ok(Output::Plain(out)),
+        }
+    }
+
+    /// Decompresses the file from its current position, up to `limit` bytes.
+    pub fn decoder(self, file: File, limit: u64) -> Result<Box<dyn Read>, Error> {
+        let file = file.take(limit);
+        if let Some(mut command) = self.program() {
+            let mut child = command
+                .arg("-dc")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to run {}", self))?;
+            let input = child.stdin.take().expect("We asked for stdin");
+            let output = child.stdout.take().expect("We asked for stdout");
+            // Feeding from a thread, the program may block on writing its output otherwise
+            let feeder = thread::spawn(move || {
+                let mut file = file;
+                let mut input = input;
+                io::copy(&mut file, &mut input).map(|_| ())
+            });
+            return Ok(Box::new(FilterReader {
+                child,
+                feeder: Some(feeder),
+                output,
+            }));
+        }
+
+        match self {
+            Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(file))),
+            _ => Ok(Box::new(file)),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            _ => bail!("Unknown compression {}", s),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Output of an external decompression program.
+///
+/// Reports a failure of the program at the end of the data.
+struct FilterReader {
+    child: Child,
+    feeder: Option<JoinHandle<IoResult<()>>>,
+    output: ChildStdout,
+}
+
+impl Read for FilterReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = self.output.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            if let Some(feeder) = self.feeder.take() {
+                feeder
+                    .join()
+                    .unwrap_or_else(|_| Err(IoError::other("Decompression feeder panicked")))?;
+            }
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(IoError::other(format!("Decompression failed: {}", status)));
+            }
+        }
+        Ok(len)
+    }
+}
+
+enum Output {
+    /// Nothing written since the last checkpoint, the compression stream is not started yet.
+    Idle(File),
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    /// Compressed by an external program writing into the file.
+    Filter {
+        file: File,
+        child: Child,
+        input: BufWriter<ChildStdin>,
+    },
+}
+
+/// Is there an executable of the name in one of the directories of the `PATH` value?
+fn on_path(name: &str, path: Option<&OsStr>) -> bool {
+    let executable = |dir: PathBuf| {
+        fs::metadata(dir.join(name))
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    path.is_some_and(|path| env::split_paths(path).any(executable))
+}
+
+/// An mbox archive file, possibly compressed.
+///
+/// The file is either opened for appending, or rewritten into a temporary file that replaces the
+/// original one at the first checkpoint. Existing content is preserved in both cases.
+pub struct Archive {
+    path: PathBuf,
+    /// The file to replace by the temporary one, when rewriting.
+    target: Option<PathBuf>,
+    compression: Compression,
+    level: Option<u32>,
+    out: Option<Output>,
+    durable_len: u64,
+}
+
+impl Archive {
+    /// Opens (or creates) the archive for appending.
+    ///
+    /// The default level of the compression is used if `level` is not set.
+    pub fn open(path: &Path, compression: Compression, level: Option<u32>) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(false)
+            .create(true)
+            .truncate(false)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        let durable_len = file
+            .metadata()
+            .with_context(|| format!("Failed to examine {}", path.display()))?
+            .len();
+        Ok(Self {
+            path: path.to_owned(),
+            target: None,
+            compression,
+            level,
+            out: Some(Output::Idle(file)),
+            durable_len,
+        })
+    }
+
+    /// Opens the archive for rewriting.
+    ///
+    /// The existing content is decompressed and copied to a temporary file, compressed as a single
+    /// stream together with whatever is written until the first checkpoint. The temporary file
+    /// then atomically replaces the original.
+    ///
+    /// The temporary file is removed if the archive is dropped before that.
+    pub fn rewrite(
+        path: &Path,
+        compression: Compression,
+        level: Option<u32>,
+    ) -> Result<Self, Error> {
+        let tmp = Self::tmp_path(path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        let mut archive = Self {
+            path: tmp,
+            target: Some(path.to_owned()),
+            compression,
+            level,
+            out: Some(compression.encoder(file, level)?),
+            durable_len: 0,
+        };
+
+        match File::open(path) {
+            Ok(old) => {
+                let len = old
+                    .metadata()
+                    .with_context(|| format!("Failed to examine {}", path.display()))?
+                    .len();
+                io::copy(&mut compression.decoder(old, len)?, &mut archive)
+                    .with_context(|| format!("Failed to copy content of {}", path.display()))?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(Error::from(e).context(format!("Failed to read {}", path.display())))
+            }
+        }
+
+        Ok(archive)
+    }
+
+    /// The temporary file used when rewriting the archive.
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".decay-tmp");
+        PathBuf::from(tmp)
+    }
+
+    /// Removes a temporary file left behind by an interrupted rewrite of the archive.
+    pub fn remove_stale(path: &Path) -> Result<(), Error> {
+        let tmp = Self::tmp_path(path);
+        match fs::remove_file(&tmp) {
+            Ok(()) => {
+                warn!("Removed {} left by an interrupted run", tmp.display());
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::from(e).context(format!("Failed to remove {}", tmp.display()))),
+        }
+    }
+
+    /// Opens an archive for reading, decompressing it on the fly.
+    pub fn reader(path: &Path, compression: Compression) -> Result<Box<dyn Read>, Error> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let len = file
+            .metadata()
+            .with_context(|| format!("Failed to examine {}", path.display()))?
+            .len();
+        compression.decoder(file, len)
+    }
+
+    /// The path to the archive file.
+    pub fn path(&self) -> &Path {
+        self.target.as_deref().unwrap_or(&self.path)
+    }
+
+    /// The length of the file as of the last checkpoint (or opening).
+    ///
+    /// Everything up to this point is safely on the disk.
+    pub fn durable_len(&self) -> u64 {
+        self.durable_len
+    }
+
+    /// Finishes the compression, flushes the buffers and syncs the file to the disk.
+    fn close(&mut self) -> Result<File, Error> {
+        let out = self
+            .out
+            .take()
+            .expect("Archive used after failed checkpoint");
+        let buffered = match out {
+            Output::Idle(file) => BufWriter::new(file),
+            Output::Plain(out) => out,
+            Output::Gzip(out) => out.finish().context("Failed to finish compression")?,
+            Output::Filter {
+                file,
+                mut child,
+                input,
+            } => {
+                // Closing the input lets the program finish
+                input
+                    .into_inner()
+                    .map_err(|e| e.into_error())
+                    .context("Failed to feed the compression")?;
+                let status = child.wait().context("Failed to finish compression")?;
+                ensure!(status.success(), "Compression failed: {}", status);
+                BufWriter::new(file)
+            }
+        };
+        let file = buffered
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to flush the archive")?;
+        file.sync_all().context("Failed to sync the archive")?;
+        if let Some(target) = &self.target {
+            fs::rename(&self.path, target).with_context(|| {
+                format!(
+                    "Failed to replace {} by {}",
+                    target.display(),
+                    self.path.display()
+                )
+            })?;
+            self.path = self.target.take().expect("Target just used");
+            let dir = match self.path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .with_context(|| format!("Failed to sync {}", dir.display()))?;
+        }
+        self.durable_len = file
+            .metadata()
+            .context("Failed to examine the archive")?
+            .len();
+        Ok(file)
+    }
+
+    /// Makes sure everything written so far is on the disk.
+    ///
+    /// A compressed archive has its current compression stream finished and a new one is started
+    /// for further writes. A rewritten archive replaces the original one. Returns the new length
+    /// of the file.
+    pub fn checkpoint(&mut self) -> Result<u64, Error> {
+        let file = self.close()?;
+        self.out = Some(Output::Idle(file));
+        Ok(self.durable_len)
+    }
+
+    /// Completes the archive.
+    ///
+    /// This must be called at the end, otherwise errors from writing out the last part of the
+    /// archive go unnoticed. Returns the final length of the file.
+    pub fn finish(mut self) -> Result<u64, Error> {
+        self.close()
+            .with_context(|| format!("Failed to finish {}", self.path.display()))?;
+        Ok(self.durable_len)
+    }
+
+    /// Reads the content of the archive written after the given offset, decompressed.
+    ///
+    /// Only the content up to the last checkpoint is seen.
+    pub fn read_from(&self, start: u64) -> Result<Vec<u8>, Error> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        file.seek(SeekFrom::Start(start))
+            .with_context(|| format!("Failed to seek in {}", self.path.display()))?;
+        let mut data = Vec::new();
+        self.compression
+            .decoder(file, self.durable_len.saturating_sub(start))?
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        Ok(data)
+    }
+
+    /// Drops everything after the given offset, which must be at a checkpoint.
+    pub fn rollback(&mut self, len: u64) -> Result<(), Error> {
+        self.checkpoint()?;
+        Self::truncate(&self.path, len)?;
+        self.durable_len = len;
+        Ok(())
+    }
+
+    /// Cuts the archive file back to the given length, dropping anything written after it.
+    pub fn truncate(path: &Path, len: u64) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.set_len(len)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to truncate {}", path.display()))
+    }
+}
+
+impl Archive {
+    /// The writer of the current compression stream, starting one if needed.
+    fn writer(&mut self) -> IoResult<&mut dyn Write> {
+        let out = self
+            .out
+            .take()
+            .expect("Archive used after failed checkpoint");
+        let out = match out {
+            Output::Idle(file) => {
+                let spare = file.try_clone();
+                match self.compression.encoder(file, self.level) {
+                    Ok(out) => out,
+                    Err(e) => {
+                        // Keep the archive usable for another attempt
+                        self.out = spare.ok().map(Output::Idle);
+                        return Err(IoError::other(format!("{:#}", e)));
+                    }
+                }
+            }
+            out => out,
+        };
+        match self.out.insert(out) {
+            Output::Idle(_) => unreachable!("Encoder just started"),
+            Output::Plain(out) => Ok(out),
+            Output::Gzip(out) => Ok(out),
+            Output::Filter { input, .. } => Ok(input),
+        }
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        // A rewrite that didn't get to replacing the original
+        if self.target.is_some() {
+            self.out.take();
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Write for Archive {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.writer()?.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer()?.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+    use crate::testdir::TempDir;
+
+    #[test]
+    fn programs() {
+        Compression::None.check_program().unwrap();
+        Compression::Gzip.check_program().unwrap();
+        let dir = TempDir::new("archive-programs");
+        let path = env::join_paths([Path::new("/nonexistent"), dir.path()]).unwrap();
+        assert!(!on_path("xz", Some(&path)));
+        assert!(!on_path("xz", None));
+        fs::write(dir.path().join("xz"), "#!/bin/sh\n").unwrap();
+        assert!(!on_path("xz", Some(&path)));
+        fs::set_permissions(dir.path().join("xz"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(on_path("xz", Some(&path)));
+    }
+
+    #[test]
+    fn rewritten() {
+        let dir = TempDir::new("archive-rewrite");
+        let path = dir.path().join("archive.gz");
+        let tmp = dir.path().join("archive.gz.decay-tmp");
+        for part in &["first\n", "second\n"] {
+            let mut archive = Archive::open(&path, Compression::Gzip, None).unwrap();
+            archive.write_all(part.as_bytes()).unwrap();
+            archive.finish().unwrap();
+        }
+        // Dropped before replacing the archive
+        let mut archive = Archive::rewrite(&path, Compression::Gzip, None).unwrap();
+        archive.write_all(b"lost\n").unwrap();
+        assert!(tmp.exists());
+        drop(archive);
+        assert!(!tmp.exists());
+
+        let before = fs::read(&path).unwrap();
+        Archive::rewrite(&path, Compression::Gzip, None)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert!(!tmp.exists());
+        let after = fs::read(&path).unwrap();
+        assert_ne!(before, after);
+        let archive = Archive::open(&path, Compression::Gzip, None).unwrap();
+        assert_eq!(b"first\nsecond\n", &archive.read_from(0).unwrap()[..]);
+
+        fs::write(&tmp, "stale").unwrap();
+        Archive::remove_stale(&path).unwrap();
+        assert!(!tmp.exists());
+        Archive::remove_stale(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = TempDir::new("archive-truncate");
+        let path = dir.path().join("archive");
+        fs::write(&path, "keep this, drop that").unwrap();
+        Archive::truncate(&path, 9).unwrap();
+        assert_eq!(b"keep this", &fs::read(&path).unwrap()[..]);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let dir = TempDir::new("archive-checkpoint");
+        let path = dir.path().join("archive.gz");
+        let mut archive = Archive::open(&path, Compression::Gzip, None).unwrap();
+        assert_eq!(0, archive.durable_len());
+        archive.write_all(b"first\n").unwrap();
+        let first = archive.checkpoint().unwrap();
+        assert_eq!(first, fs::metadata(&path).unwrap().len());
+        archive.write_all(b"second\n").unwrap();
+        let second = archive.checkpoint().unwrap();
+        assert!(second > first);
+        assert_eq!(second, archive.durable_len());
+        // Each checkpoint finished a complete compression stream
+        let mut content = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!("first\nsecond\n", content);
+    }
+
+    #[test]
+    fn rolled_back() {
+        for name in &["archive", "archive.gz"] {
+            let dir = TempDir::new("archive-rollback");
+            let path = dir.path().join(name);
+            let compression = Compression::from_path(&path);
+            let mut archive = Archive::open(&path, compression, None).unwrap();
+            archive.write_all(b"first\n").unwrap();
+            let first = archive.checkpoint().unwrap();
+            archive.write_all(b"second\n").unwrap();
+            archive.checkpoint().unwrap();
+            assert_eq!(b"second\n", &archive.read_from(first).unwrap()[..]);
+
+            archive.rollback(first).unwrap();
+            assert_eq!(first, fs::metadata(&path).unwrap().len());
+            // Still usable after that
+            archive.write_all(b"third\n").unwrap();
+            archive.finish().unwrap();
+            let reopened = Archive::open(&path, compression, None).unwrap();
+            assert_eq!(b"first\nthird\n", &reopened.read_from(0).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn guessed_and_checked() {
+        let guess = |path| Compression::from_path(Path::new(path));
+        assert_eq!(Compression::Zstd, guess("mail/archive.mbox.zst"));
+        assert_eq!(Compression::None, guess("archive.mbox"));
+        assert_eq!(Compression::Bzip2, "bzip2".parse().unwrap());
+        Compression::Zstd.check_level(Some(19)).unwrap();
+        Compression::None.check_level(None).unwrap();
+        assert!(Compression::Gzip.check_level(Some(10)).is_err());
+        assert!(Compression::None.check_level(Some(1)).is_err());
+    }
+
+    #[test]
+    fn external_programs() {
+        for &compression in &[Compression::Xz, Compression::Zstd, Compression::Bzip2] {
+            // Not installed everywhere
+            if compression.check_program().is_err() {
+                continue;
+            }
+            let dir = TempDir::new("archive-external");
+            let path = dir.path().join("archive");
+            let mut archive = Archive::open(&path, compression, Some(1)).unwrap();
+            archive.write_all(b"first\n").unwrap();
+            archive.checkpoint().unwrap();
+            archive.write_all(b"second\n").unwrap();
+            archive.finish().unwrap();
+            let reopened = Archive::open(&path, compression, None).unwrap();
+            assert_eq!(b"first\nsecond\n", &reopened.read_from(0).unwrap()[..]);
+        }
+    }
+}
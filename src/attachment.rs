@@ -0,0 +1,335 @@
+//! Stripping big attachments from the archived messages.
+//!
+//! The attachments over the size limit are replaced by a short `text/plain` part with their name,
+//! type, size and SHA-256 hash, and optionally saved into a directory under the hash. Everything
+//! else in the message stays byte for byte the same. Each replaced attachment is listed in an
+//! `X-Decay-Stripped` header added to the top of the message.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use mailparse::body::Body;
+use mailparse::{
+    parse_content_disposition, parse_content_type, parse_mail, DispositionType, MailHeaderMap,
+    ParsedMail,
+};
+
+use crate::sha256;
+
+/// What to do with the big attachments.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Policy {
+    /// Leave them in the message.
+    Keep,
+    /// Replace them by a placeholder.
+    Strip,
+    /// Replace them by a placeholder and save them into a directory.
+    Extract,
+}
+
+impl Policy {
+    pub const NAMES: &'static [&'static str] = &["keep", "strip", "extract"];
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "keep" => Ok(Policy::Keep),
+            "strip" => Ok(Policy::Strip),
+            "extract" => Ok(Policy::Extract),
+            _ => bail!("Unknown attachment policy {}", s),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Policy::Keep => "keep",
+            Policy::Strip => "strip",
+            Policy::Extract => "extract",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// A part to be replaced, as a byte range of the message.
+struct Replaced {
+    start: usize,
+    end: usize,
+    part: Vec<u8>,
+    header: String,
+}
+
+/// Where the slice lives inside the whole message.
+fn offset(whole: &[u8], part: &[u8]) -> Option<usize> {
+    let start = (part.as_ptr() as usize).checked_sub(whole.as_ptr() as usize)?;
+    Some(start).filter(|start| start + part.len() <= whole.len())
+}
+
+/// The first value of the header of the part.
+///
+/// The values are trimmed, as the parser leaves the CR in them in parts with CRLF line endings.
+fn header(part: &ParsedMail, name: &str) -> String {
+    part.headers
+        .get_first_value(name)
+        .map_or_else(String::new, |value| value.trim().to_owned())
+}
+
+/// The content of the part, without the transfer encoding.
+fn decode(part: &ParsedMail, body: &[u8]) -> Result<Vec<u8>, Error> {
+    // Decoded as a message of its own, to get the encoding header without the CR
+    let encoding = header(part, "Content-Transfer-Encoding");
+    let mut single = format!("Content-Transfer-Encoding: {}\n\n", encoding).into_bytes();
+    single.extend_from_slice(body);
+    Ok(parse_mail(&single)?.get_body_raw()?)
+}
+
+/// Cleans up a value for use in a header or the placeholder.
+fn clean(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+/// Saves the content of an attachment into the directory, named by its hash.
+fn extract(dir: &Path, hash: &str, data: &[u8]) -> Result<(), Error> {
+    let path = dir.join(hash);
+    if path.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let tmp = dir.join(format!(".{}.tmp", hash));
+    File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, &path))
+        .and_then(|()| File::open(dir)?.sync_all())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+impl Policy {
+    /// Finds the attachments to replace in the part and its subparts.
+    fn collect(
+        self,
+        whole: &[u8],
+        part: &ParsedMail,
+        limit: u64,
+        dir: &Path,
+        eol: &str,
+        replaced: &mut Vec<Replaced>,
+    ) -> Result<(), Error> {
+        if !part.subparts.is_empty() {
+            for sub in &part.subparts {
+                self.collect(whole, sub, limit, dir, eol, replaced)?;
+            }
+            return Ok(());
+        }
+        let ctype = parse_content_type(&header(part, "Content-Type"));
+        let disposition = parse_content_disposition(&header(part, "Content-Disposition"));
+        let attached = disposition.disposition == DispositionType::Attachment;
+        if ctype.mimetype.starts_with("text/") && !attached {
+            return Ok(());
+        }
+        let raw = match part.get_body_encoded() {
+            Body::Base64(body) | Body::QuotedPrintable(body) => body.get_raw(),
+            Body::SevenBit(body) | Body::EightBit(body) => body.get_raw(),
+            Body::Binary(body) => body.get_raw(),
+        };
+        let (first, body) = match (part.headers.first(), offset(whole, raw)) {
+            (Some(first), Some(body)) => (first, body),
+            // A part without headers is plain text
+            _ => return Ok(()),
+        };
+        let start = match offset(whole, first.get_key_raw()) {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let data = decode(part, raw).context("Can't decode attachment")?;
+        if (data.len() as u64) <= limit {
+            return Ok(());
+        }
+        let hash = sha256::hex(&data);
+        if self == Policy::Extract {
+            extract(dir, &hash, &data)?;
+        }
+        let name = disposition
+            .params
+            .get("filename")
+            .or_else(|| ctype.params.get("name"))
+            .map_or_else(|| "unnamed".to_owned(), |name| clean(name));
+        let mimetype = clean(&ctype.mimetype);
+        let mut text = format!(
+            "Content-Type: text/plain; charset=utf-8{eol}\
+             Content-Disposition: inline{eol}{eol}\
+             The attachment was removed from the archive.{eol}{eol}\
+             Name: {}{eol}Type: {}{eol}Size: {} bytes{eol}SHA-256: {}{eol}",
+            name,
+            mimetype,
+            data.len(),
+            hash,
+            eol = eol
+        );
+        if self == Policy::Extract {
+            text.push_str(&format!("Extracted to: {}{}", hash, eol));
+        }
+        let end = body + raw.len();
+        // The line break before the next boundary belongs to the boundary
+        if !raw.ends_with(b"\n") {
+            text.truncate(text.len() - eol.len());
+        }
+        let header = format!(
+            "X-Decay-Stripped: {}; name=\"{}\"; type={}; size={}; sha256={}{}",
+            self,
+            name.replace('\\', "\\\\").replace('"', "\\\""),
+            mimetype,
+            data.len(),
+            hash,
+            eol
+        );
+        replaced.push(Replaced {
+            start,
+            end,
+            part: text.into_bytes(),
+            header,
+        });
+        Ok(())
+    }
+
+    /// Applies the policy to the raw message.
+    ///
+    /// Attachments bigger than `limit` bytes are replaced. The `dir` is where they are extracted
+    /// to. Messages that can't be parsed are left as they are.
+    pub fn apply(self, raw: Vec<u8>, limit: u64, dir: &Path) -> Result<Vec<u8>, Error> {
+        if self == Policy::Keep {
+            return Ok(raw);
+        }
+        let mut replaced = Vec::new();
+        {
+            let parsed = match parse_mail(&raw) {
+                Ok(parsed) if !parsed.subparts.is_empty() => parsed,
+                _ => return Ok(raw),
+            };
+            // Don't strip again what was already stripped once
+            if !parsed.headers.get_all_values("X-Decay-Stripped").is_empty() {
+                return Ok(raw);
+            }
+            let eol = match raw.iter().position(|&b| b == b'\n') {
+                Some(pos) if pos > 0 && raw[pos - 1] == b'\r' => "\r\n",
+                _ => "\n",
+            };
+            self.collect(&raw, &parsed, limit, dir, eol, &mut replaced)?;
+        }
+        if replaced.is_empty() {
+            return Ok(raw);
+        }
+        replaced.sort_by_key(|r| r.start);
+        let mut out = Vec::with_capacity(raw.len());
+        for r in &replaced {
+            out.extend_from_slice(r.header.as_bytes());
+        }
+        let mut pos = 0;
+        for r in replaced {
+            out.extend_from_slice(&raw[pos..r.start]);
+            out.extend_from_slice(&r.part);
+            pos = r.end;
+        }
+        out.extend_from_slice(&raw[pos..]);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    const MAIL: &str = "From: a@x\r\n\
+        Subject: Report\r\n\
+        Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+        \r\n\
+        --b\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        See the attachment.\r\n\
+        --b\r\n\
+        Content-Type: application/pdf; name=\"r.pdf\"\r\n\
+        Content-Disposition: attachment; filename=\"r.pdf\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        aGVsbG8gd29ybGQ=\r\n\
+        --b--\r\n";
+
+    // sha256 of "hello world"
+    const HASH: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn strip() {
+        let dir = Path::new("/nonexistent");
+        let stripped = Policy::Strip.apply(MAIL.into(), 5, dir).unwrap();
+        let stripped = String::from_utf8(stripped).unwrap();
+        let expected = format!(
+            "X-Decay-Stripped: strip; name=\"r.pdf\"; type=application/pdf; size=11; \
+             sha256={hash}\r\n\
+             From: a@x\r\n\
+             Subject: Report\r\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             See the attachment.\r\n\
+             --b\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Disposition: inline\r\n\
+             \r\n\
+             The attachment was removed from the archive.\r\n\
+             \r\n\
+             Name: r.pdf\r\n\
+             Type: application/pdf\r\n\
+             Size: 11 bytes\r\n\
+             SHA-256: {hash}\r\n\
+             --b--\r\n",
+            hash = HASH
+        );
+        assert_eq!(stripped, expected);
+        // Once is enough
+        let again = Policy::Strip
+            .apply(stripped.clone().into(), 5, dir)
+            .unwrap();
+        assert_eq!(again, stripped.as_bytes());
+    }
+
+    #[test]
+    fn small_and_kept() {
+        let dir = Path::new("/nonexistent");
+        for (policy, limit) in [(Policy::Strip, 11), (Policy::Keep, 0)] {
+            let result = policy.apply(MAIL.into(), limit, dir).unwrap();
+            assert_eq!(result, MAIL.as_bytes());
+        }
+        let plain = "Subject: x\n\nJust text\n";
+        let result = Policy::Strip.apply(plain.into(), 0, dir).unwrap();
+        assert_eq!(result, plain.as_bytes());
+    }
+
+    #[test]
+    fn extract_file() {
+        let tmp = TempDir::new("attachment-extract");
+        let dir = tmp.path().join("attachments");
+        let result = Policy::Extract.apply(MAIL.into(), 5, &dir).unwrap();
+        let result = String::from_utf8(result).unwrap();
+        assert!(result.starts_with("X-Decay-Stripped: extract; "));
+        assert!(result.contains(&format!("Extracted to: {}\r\n--b--", HASH)));
+        assert_eq!(fs::read(dir.join(HASH)).unwrap(), b"hello world");
+    }
+}
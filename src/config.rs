@@ -0,0 +1,648 @@
+//! The configuration file with multiple jobs.
+//!
+//! The file uses a subset of TOML. Each job is a `[job.<name>]` table, its keys are the long
+//! command line options (`age = 14`, `archive = "..."`, `remove = true`), except for `maildir`
+//! standing for `--dir`. The keys before the first job are defaults shared by all the jobs. For
+//! example:
+//!
+//! ```toml
+//! recursive = true
+//!
+//! [job.lists]
+//! maildir = ["~/Mail/.Lists.*"]
+//! age = 14
+//! archive = "~/archive/{folder}-%Y.mbox.gz"
+//!
+//! [job.trash]
+//! maildir = "~/Mail/.Trash"
+//! age = 7
+//! remove = true
+//! ```
+//!
+//! Paths may start with `~/` and the maildirs may contain the `*` and `?` wildcards.
+//!
+//! Only strings, integers, booleans and arrays (which may span multiple lines) are supported.
+//! The rest of TOML (dotted keys, inline tables, multi-line strings, floats, dates, ...) is
+//! rejected.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Error};
+
+/// The options that can be set in the configuration file.
+///
+/// Things like --confirm are left for the command line.
+const KEYS: &[&str] = &[
+    "maildir",
+    "archive",
+    "archive-maildir",
+    "archive-eml",
+    "quarantine",
+    "parse-report",
+    "compression",
+    "compression-level",
+    "mbox-format",
+    "single-stream",
+    "journal",
+    "batch-size",
+    "verify",
+    "remove",
+    "trash",
+    "trash-flag",
+    "grace",
+    "recursive",
+    "threads",
+    "keep-flagged-threads",
+    "keep-newest",
+    "keep-by",
+    "max-size",
+    "dedup",
+    "dedup-index",
+    "attachments",
+    "attachment-size",
+    "new",
+    "age",
+    "date-source",
+    "before",
+    "after",
+    "select",
+];
+
+/// The options choosing what happens to the messages, only one of them may be used.
+pub const ACTIONS: &[&str] = &["archive", "archive-maildir", "archive-eml", "remove"];
+
+/// Options with paths, to expand `~` in.
+const PATHS: &[&str] = &[
+    "maildir",
+    "archive",
+    "archive-maildir",
+    "archive-eml",
+    "quarantine",
+    "parse-report",
+    "trash",
+    "dedup-index",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// A single job from the config file.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub name: String,
+    settings: Vec<(String, Value)>,
+}
+
+/// The parsed configuration file.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    defaults: Vec<(String, Value)>,
+    pub jobs: Vec<Job>,
+}
+
+fn parse_string(input: &str, quote: char) -> Result<(String, &str), Error> {
+    let mut result = String::new();
+    let mut chars = input.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((result, &input[idx + 1..])),
+            '\\' if quote == '"' => match chars.next() {
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                Some((_, 'r')) => result.push('\r'),
+                Some((_, 'b')) => result.push('\u{8}'),
+                Some((_, 'f')) => result.push('\u{c}'),
+                Some((_, c @ '"')) | Some((_, c @ '\\')) => result.push(c),
+                Some((_, u @ 'u')) | Some((_, u @ 'U')) => {
+                    let len = if u == 'u' { 4 } else { 8 };
+                    let hex = chars.by_ref().take(len).map(|(_, c)| c);
+                    let hex = hex.collect::<String>();
+                    let valid = hex.len() == len && hex.chars().all(|c| c.is_ascii_hexdigit());
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .filter(|_| valid)
+                        .and_then(char::from_u32)
+                        .with_context(|| format!("Invalid escape sequence \\{}{}", u, hex))?;
+                    result.push(c);
+                }
+                Some((_, c)) => bail!("Invalid escape sequence \\{}", c),
+                None => break,
+            },
+            c => result.push(c),
+        }
+    }
+    bail!("Unterminated string")
+}
+
+/// Parses an integer, in the decimal syntax of TOML.
+fn parse_integer(word: &str) -> Result<i64, Error> {
+    let digits = word.strip_prefix(|c| c == '+' || c == '-').unwrap_or(word);
+    let valid = digits.starts_with(|c: char| c.is_ascii_digit())
+        && digits.ends_with(|c: char| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '_')
+        && !digits.contains("__")
+        && !(digits.len() > 1 && digits.starts_with('0'));
+    ensure!(
+        valid,
+        "Unsupported value {} (only strings, integers, booleans and arrays are supported)",
+        word
+    );
+    word.replace('_', "")
+        .parse()
+        .with_context(|| format!("Invalid value {}", word))
+}
+
+/// Parses a value at the start of the input, returns the rest.
+fn parse_value(input: &str) -> Result<(Value, &str), Error> {
+    let input = input.trim_start();
+    ensure!(
+        !input.starts_with("\"\"\"") && !input.starts_with("'''"),
+        "Multi-line strings are not supported"
+    );
+    ensure!(!input.starts_with('{'), "Inline tables are not supported");
+    if let Some(rest) = input.strip_prefix('"') {
+        let (s, rest) = parse_string(rest, '"')?;
+        Ok((Value::String(s), rest))
+    } else if let Some(rest) = input.strip_prefix('\'') {
+        let (s, rest) = parse_string(rest, '\'')?;
+        Ok((Value::String(s), rest))
+    } else if let Some(mut rest) = input.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else {
+                ensure!(rest.starts_with(']'), "Expected , or ] in array");
+            }
+        }
+    } else {
+        let end = input
+            .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+            .unwrap_or(input.len());
+        let (word, rest) = input.split_at(end);
+        let value = match word {
+            "" => bail!("Missing value"),
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => Value::Integer(parse_integer(word)?),
+        };
+        Ok((value, rest))
+    }
+}
+
+/// Strips a comment from the end of the line, unless the `#` is in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..idx],
+            _ => (),
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// How many more arrays the line opens than it closes, not counting brackets in strings.
+fn open_arrays(line: &str) -> i32 {
+    let mut open = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '[') => open += 1,
+            (None, ']') => open -= 1,
+            _ => (),
+        }
+        escaped = false;
+    }
+    open
+}
+
+/// Is this a bare key (or job name)?
+fn is_bare(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Config {
+    /// Parses the content of a configuration file.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut config = Config::default();
+        // An array spanning multiple lines, with the number of its first line
+        let mut unfinished: Option<(usize, String)> = None;
+        for (lineno, line) in text.lines().enumerate() {
+            let line = strip_comment(line);
+            let (lineno, line) = match unfinished.take() {
+                Some((start, mut joined)) => {
+                    joined.push(' ');
+                    joined.push_str(line);
+                    (start, joined)
+                }
+                None => (lineno, line.to_owned()),
+            };
+            let table = line.trim_start().starts_with('[');
+            if !table && line.contains('=') && open_arrays(&line) > 0 {
+                unfinished = Some((lineno, line));
+                continue;
+            }
+            let parsed = config.parse_line(&line);
+            parsed.with_context(|| format!("Error on line {}", lineno + 1))?;
+        }
+        if let Some((lineno, _)) = unfinished {
+            bail!("Error on line {}: Unterminated array", lineno + 1);
+        }
+        Ok(config)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        ensure!(
+            !line.starts_with("[["),
+            "Arrays of tables are not supported"
+        );
+        if let Some(table) = line.strip_prefix('[') {
+            let table = table
+                .strip_suffix(']')
+                .context("Unterminated table header")?;
+            let name = table
+                .trim()
+                .strip_prefix("job.")
+                .context("Only [job.<name>] tables are supported")?
+                .trim();
+            let name = match name.strip_prefix('"') {
+                Some(quoted) => {
+                    let (name, rest) = parse_string(quoted, '"')?;
+                    ensure!(rest.is_empty(), "Only [job.<name>] tables are supported");
+                    name
+                }
+                None => {
+                    ensure!(
+                        is_bare(name),
+                        "Invalid job name {} (nested tables are not supported)",
+                        name
+                    );
+                    name.to_owned()
+                }
+            };
+            ensure!(!name.is_empty(), "Empty job name");
+            ensure!(
+                self.jobs.iter().all(|job| job.name != name),
+                "Duplicate job {}",
+                name
+            );
+            self.jobs.push(Job {
+                name,
+                settings: Vec::new(),
+            });
+            return Ok(());
+        }
+
+        let eq = line.find('=').context("Expected key = value")?;
+        let key = line[..eq].trim();
+        ensure!(
+            is_bare(key),
+            "Invalid key {} (dotted and quoted keys are not supported)",
+            key
+        );
+        ensure!(KEYS.contains(&key), "Unknown option {}", key);
+        let (value, rest) = parse_value(&line[eq + 1..])?;
+        ensure!(
+            rest.trim().is_empty(),
+            "Garbage after value: {}",
+            rest.trim()
+        );
+        let settings = match self.jobs.last_mut() {
+            Some(job) => &mut job.settings,
+            None => &mut self.defaults,
+        };
+        ensure!(
+            settings.iter().all(|(k, _)| k != key),
+            "Duplicate option {}",
+            key
+        );
+        settings.push((key.to_owned(), value));
+        Ok(())
+    }
+
+    /// Reads and parses the configuration file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let config =
+            Self::parse(&text).with_context(|| format!("Invalid config {}", path.display()))?;
+        ensure!(!config.jobs.is_empty(), "No jobs in {}", path.display());
+        Ok(config)
+    }
+
+    /// Turns the job into command line arguments (not including the program name).
+    ///
+    /// The defaults the job sets itself are left out, and so are the options `overridden` says are
+    /// set on the command line.
+    pub fn args<F>(&self, job: &Job, overridden: F) -> Result<Vec<OsString>, Error>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut args = Vec::new();
+        let defaults = self
+            .defaults
+            .iter()
+            .filter(|(key, _)| job.settings.iter().all(|(k, _)| k != key));
+        for (key, value) in defaults.chain(&job.settings) {
+            if overridden(key) {
+                continue;
+            }
+            push_arg(&mut args, key, value)
+                .with_context(|| format!("Invalid option {} in job {}", key, job.name))?;
+        }
+        Ok(args)
+    }
+}
+
+fn push_arg(args: &mut Vec<OsString>, key: &str, value: &Value) -> Result<(), Error> {
+    // The maildir is --dir on the command line
+    let option = match key {
+        "maildir" => OsString::from("--dir"),
+        _ => OsString::from(format!("--{}", key)),
+    };
+    match value {
+        Value::Boolean(true) => args.push(option),
+        Value::Boolean(false) => (),
+        // Joined, a negative number would look like an option otherwise
+        Value::Integer(i) => {
+            let mut arg = option;
+            arg.push(format!("={}", i));
+            args.push(arg);
+        }
+        Value::String(s) if key == "maildir" => {
+            let matches = glob(&expand_home(s))?;
+            ensure!(!matches.is_empty(), "No maildir matches {}", s);
+            for path in matches {
+                args.extend(vec![option.clone(), path.into_os_string()]);
+            }
+        }
+        Value::String(s) if PATHS.contains(&key) => {
+            args.extend(vec![option, expand_home(s).into_os_string()])
+        }
+        Value::String(s) => args.extend(vec![option, OsString::from(s)]),
+        Value::Array(values) => {
+            ensure!(key == "maildir", "Only maildir can have multiple values");
+            for value in values {
+                ensure!(
+                    matches!(value, Value::String(_)),
+                    "Maildirs must be strings"
+                );
+                push_arg(args, key, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Does the name match the pattern with `*` and `?` wildcards?
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was and the position in the name it is tried to match up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Let the last `*` take one more character and try again from there
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Expands wildcards in the path into the existing directories.
+///
+/// A path without wildcards is returned as it is, even if it doesn't exist.
+fn glob(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut found = vec![PathBuf::new()];
+    for component in path.iter() {
+        let pattern = component.as_bytes();
+        if !pattern.contains(&b'*') && !pattern.contains(&b'?') {
+            found.iter_mut().for_each(|p| p.push(component));
+            continue;
+        }
+        let mut expanded = Vec::new();
+        for dir in &found {
+            let listed = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            let entries = match fs::read_dir(listed) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let mut names = entries
+                .filter_map(Result::ok)
+                .map(|e| e.file_name())
+                .filter(|name| wildcard(pattern, name.as_bytes()))
+                .collect::<Vec<_>>();
+            names.sort();
+            expanded.extend(names.into_iter().map(|name| dir.join(name)));
+        }
+        found = expanded;
+    }
+    found.retain(|p| !p.as_os_str().is_empty());
+    if path
+        .iter()
+        .any(|c| c.as_bytes().contains(&b'*') || c.as_bytes().contains(&b'?'))
+    {
+        found.retain(|p| p.is_dir());
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::clap::ErrorKind;
+    use structopt::StructOpt;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+# Shared by all jobs
+recursive = true
+
+[job.lists]
+maildir = ["/tmp/lists", '/tmp/other'] # Comment
+age = 14
+archive = "/tmp/archive-#%Y.gz"
+
+[job."trash"]
+maildir = "/tmp/trash"
+remove = true
+new = false
+"#;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(2, config.jobs.len());
+        assert_eq!("lists", config.jobs[0].name);
+        assert_eq!("trash", config.jobs[1].name);
+
+        let args = config.args(&config.jobs[0], |_| false).unwrap();
+        let expected = [
+            "--recursive",
+            "--dir",
+            "/tmp/lists",
+            "--dir",
+            "/tmp/other",
+            "--age=14",
+            "--archive",
+            "/tmp/archive-#%Y.gz",
+        ];
+        assert_eq!(
+            expected.iter().map(OsString::from).collect::<Vec<_>>(),
+            args
+        );
+
+        let args = config.args(&config.jobs[1], |_| false).unwrap();
+        let expected = ["--recursive", "--dir", "/tmp/trash", "--remove"];
+        assert_eq!(
+            expected.iter().map(OsString::from).collect::<Vec<_>>(),
+            args
+        );
+
+        // Given on the command line instead
+        let overridden = |key: &str| key == "recursive" || key == "remove";
+        let args = config.args(&config.jobs[1], overridden).unwrap();
+        let expected = ["--dir", "/tmp/trash"];
+        assert_eq!(
+            expected.iter().map(OsString::from).collect::<Vec<_>>(),
+            args
+        );
+    }
+
+    #[test]
+    fn multiline() {
+        let text = "[job.a]\n\
+                    maildir = [\n  \"/tmp/a\", # First\n  \"/tmp/\\u00e9\\tb]\",\n]\n\
+                    age = -1_000\n";
+        let config = Config::parse(text).unwrap();
+        let args = config.args(&config.jobs[0], |_| false).unwrap();
+        let expected = ["--dir", "/tmp/a", "--dir", "/tmp/\u{e9}\tb]", "--age=-1000"];
+        assert_eq!(
+            expected.iter().map(OsString::from).collect::<Vec<_>>(),
+            args
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("age = \"unterminated").is_err());
+        assert!(Config::parse("[other]").is_err());
+        assert!(Config::parse("[job.a]\nage = 1\nage = 2").is_err());
+        assert!(Config::parse("[job.a]\n[job.a]").is_err());
+        // Outside of the supported subset
+        let unsupported = [
+            "[job.a.b]",
+            "[[job.a]]",
+            "job.age = 1",
+            "\"age\" = 1",
+            "age =",
+            "age = 1.5",
+            "age = 0x10",
+            "age = 014",
+            "age = 1979-05-27",
+            "age = inf",
+            "select = \"\"\"multi\"\"\"",
+            "select = '''multi'''",
+            "select = { a = 1 }",
+            "select = \"\\x\"",
+            "select = \"\\u00\"",
+            "maildir = [\"a\",\n",
+        ];
+        for text in &unsupported {
+            assert!(Config::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard(b".Lists.*", b".Lists.foo"));
+        assert!(wildcard(b"*", b""));
+        assert!(wildcard(b"a?c", b"abc"));
+        assert!(!wildcard(b"a?c", b"ac"));
+        assert!(!wildcard(b".Lists.*", b".Trash"));
+        assert!(wildcard(b"*a*b", b"xaxxb"));
+        assert!(!wildcard(b"*a*b", b"xaxxbx"));
+        assert!(wildcard(b"a**", b"a"));
+        // Would take ages with backtracking
+        let name = [b'a'; 100];
+        assert!(!wildcard(b"*a*a*a*a*a*a*a*a*a*a*b", &name));
+    }
+
+    #[test]
+    fn negative_integers() {
+        let config =
+            Config::parse("[job.a]\nmaildir = \"/tmp/a\"\ncompression-level = -1\n").unwrap();
+        let mut args = vec![OsString::from("decay")];
+        args.extend(config.args(&config.jobs[0], |_| false).unwrap());
+        assert_eq!(OsString::from("--compression-level=-1"), args[3]);
+        // Taken as the value of the option, not as an unknown option
+        let e = crate::Opts::from_iter_safe(args).unwrap_err();
+        assert_eq!(ErrorKind::ValueValidation, e.kind);
+    }
+}
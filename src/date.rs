@@ -0,0 +1,328 @@
+//! Calendar computations on unix timestamps (in UTC).
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Error};
+
+/// Length of a day, in seconds.
+pub const DAY: i64 = 86_400;
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A broken down timestamp.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Civil {
+    pub year: i64,
+    /// 1 to 12.
+    pub month: u32,
+    /// 1 to 31.
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Days since the epoch, for computing the day of week.
+    days: i64,
+}
+
+impl Civil {
+    /// Breaks down a unix timestamp.
+    pub fn from_timestamp(secs: i64) -> Self {
+        let days = secs.div_euclid(DAY);
+        let in_day = secs.rem_euclid(DAY) as u32;
+
+        // Conversion of days to a civil date, by Howard Hinnant's algorithm.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month,
+            day,
+            hour: in_day / 3600,
+            minute: in_day % 3600 / 60,
+            second: in_day % 60,
+            days,
+        }
+    }
+
+    /// Converts back to a unix timestamp.
+    pub fn timestamp(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * DAY
+            + i64::from(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+
+    /// The quarter of the year, 1 to 4.
+    pub fn quarter(&self) -> u32 {
+        (self.month - 1) / 3 + 1
+    }
+
+    /// Formats the time the way `ctime` does (without the trailing newline).
+    pub fn ctime(&self) -> String {
+        format!(
+            "{} {} {:>2} {:02}:{:02}:{:02} {}",
+            DAYS[self.days.rem_euclid(7) as usize],
+            MONTHS[self.month as usize - 1],
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.year,
+        )
+    }
+}
+
+/// Days since the epoch of a date, the inverse of the conversion in `from_timestamp`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    (days_from_civil(next_year, next_month, 1) - days_from_civil(year, month, 1)) as u32
+}
+
+/// Parses a date as `YYYY-MM-DD`, optionally followed by `THH:MM` or `THH:MM:SS` (in UTC).
+pub fn parse(s: &str) -> Result<i64, Error> {
+    let parse = || {
+        let (date, time) = match s.find(['T', ' ']) {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+        let date = date
+            .split('-')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()?;
+        let time = match time {
+            Some(time) => time
+                .split(':')
+                .map(str::parse)
+                .collect::<Result<Vec<u32>, _>>()?,
+            None => Vec::new(),
+        };
+        ensure!(date.len() == 3, "Expected YYYY-MM-DD");
+        ensure!(
+            time.is_empty() || time.len() == 2 || time.len() == 3,
+            "Expected HH:MM"
+        );
+        let (year, month, day) = (i64::from(date[0]), date[1], date[2]);
+        ensure!((1..=12).contains(&month), "Invalid month");
+        ensure!(
+            (1..=days_in_month(year, month)).contains(&day),
+            "Invalid day"
+        );
+        let time = [0, 1, 2].map(|i| time.get(i).copied().unwrap_or(0));
+        ensure!(time[0] < 24 && time[1] < 60 && time[2] < 60, "Invalid time");
+        Ok(days_from_civil(year, month, day) * DAY
+            + i64::from(time[0] * 3600 + time[1] * 60 + time[2]))
+    };
+    parse().with_context(|| format!("Invalid date {}", s))
+}
+
+/// Rejects a date parsed from a header that had no date in it.
+///
+/// `mailparse` reads text without anything resembling a date as the epoch.
+pub fn known(date: i64) -> Result<i64, Error> {
+    ensure!(date != 0, "No date found");
+    Ok(date)
+}
+
+/// An age of a message, like `36h`, `6w` or `18mo`.
+///
+/// Months and years are calendar ones, the rest are fixed lengths. A plain number means days.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Age {
+    Seconds(i64),
+    Months(i64),
+}
+
+impl Age {
+    /// The time the given age before `now`.
+    pub fn before(&self, now: i64) -> i64 {
+        match *self {
+            Age::Seconds(secs) => now - secs,
+            Age::Months(months) => {
+                let civil = Civil::from_timestamp(now);
+                let months = civil.year * 12 + i64::from(civil.month) - 1 - months;
+                let year = months.div_euclid(12);
+                let month = months.rem_euclid(12) as u32 + 1;
+                let civil = Civil {
+                    year,
+                    month,
+                    day: civil.day.min(days_in_month(year, month)),
+                    ..civil
+                };
+                civil.timestamp()
+            }
+        }
+    }
+
+    /// The age in days, if it is a whole number of them.
+    pub fn whole_days(&self) -> Option<i64> {
+        match *self {
+            Age::Seconds(secs) if secs % DAY == 0 => Some(secs / DAY),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Age {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: i64 = num.parse().with_context(|| format!("Invalid age {}", s))?;
+        let too_big = || format!("Age {} is too big", s);
+        let age = match unit {
+            "s" => Age::Seconds(num),
+            "min" => Age::Seconds(num.checked_mul(60).with_context(too_big)?),
+            "h" => Age::Seconds(num.checked_mul(3600).with_context(too_big)?),
+            "" | "d" => Age::Seconds(num.checked_mul(DAY).with_context(too_big)?),
+            "w" => Age::Seconds(num.checked_mul(7 * DAY).with_context(too_big)?),
+            "mo" => Age::Months(num),
+            "y" => Age::Months(num.checked_mul(12).with_context(too_big)?),
+            _ => bail!(
+                "Unknown unit {} in age {} (use s, min, h, d, w, mo or y)",
+                unit,
+                s
+            ),
+        };
+        // A million years, far below what would overflow the calendar computations
+        if let Age::Months(months) = age {
+            ensure!(months <= 12 * 1_000_000, "Age {} is too big", s);
+        }
+        Ok(age)
+    }
+}
+
+/// Where the date of a message is taken from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// The Date header.
+    Header,
+    /// The newest (topmost) Received header.
+    Received,
+    /// The delivery time stored in the maildir file name.
+    Filename,
+    /// The modification time of the file.
+    Mtime,
+}
+
+impl Source {
+    pub const NAMES: &'static [&'static str] = &["header", "received", "filename", "mtime"];
+}
+
+impl FromStr for Source {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "header" => Ok(Source::Header),
+            "received" => Ok(Source::Received),
+            "filename" => Ok(Source::Filename),
+            "mtime" => Ok(Source::Mtime),
+            _ => bail!("Unknown date source {}", s),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Source::Header => "header",
+            Source::Received => "received",
+            Source::Filename => "filename",
+            Source::Mtime => "mtime",
+        };
+        fmt.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch() {
+        let civil = Civil::from_timestamp(0);
+        assert_eq!((1970, 1, 1), (civil.year, civil.month, civil.day));
+        assert_eq!("Thu Jan  1 00:00:00 1970", civil.ctime());
+    }
+
+    #[test]
+    fn leap_day() {
+        // 2024-02-29 12:34:56
+        let civil = Civil::from_timestamp(1_709_210_096);
+        assert_eq!((2024, 2, 29), (civil.year, civil.month, civil.day));
+        assert_eq!((12, 34, 56), (civil.hour, civil.minute, civil.second));
+        assert_eq!(1, civil.quarter());
+        assert_eq!("Thu Feb 29 12:34:56 2024", civil.ctime());
+    }
+
+    #[test]
+    fn roundtrip() {
+        for &ts in &[0, -1, 1_709_210_096, 951_782_400, -5_000_000_000] {
+            assert_eq!(ts, Civil::from_timestamp(ts).timestamp());
+        }
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(1_704_067_200, parse("2024-01-01").unwrap());
+        assert_eq!(1_709_210_096, parse("2024-02-29T12:34:56").unwrap());
+        assert_eq!(1_709_210_040, parse("2024-02-29 12:34").unwrap());
+        assert!(parse("2023-02-29").is_err());
+        assert!(parse("2024-13-01").is_err());
+        assert!(parse("2024-01").is_err());
+        assert!(parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn ages() {
+        // 2024-03-31 00:00:00
+        let now = 1_711_843_200;
+        assert_eq!(Age::Seconds(30 * DAY), "30".parse().unwrap());
+        assert_eq!(now - 36 * 3600, "36h".parse::<Age>().unwrap().before(now));
+        assert_eq!(now - 42 * DAY, "6w".parse::<Age>().unwrap().before(now));
+        // 2022-09-30, the 31st doesn't exist
+        let before = "18mo".parse::<Age>().unwrap().before(now);
+        assert_eq!(parse("2022-09-30").unwrap(), before);
+        assert_eq!(
+            parse("2023-03-31").unwrap(),
+            "1y".parse::<Age>().unwrap().before(now)
+        );
+        assert!("6m".parse::<Age>().is_err());
+        assert!("106751991167301d".parse::<Age>().is_err());
+        assert!("768614336404564651y".parse::<Age>().is_err());
+        assert!("9223372036854775807mo".parse::<Age>().is_err());
+        assert_eq!(Some(42), "6w".parse::<Age>().unwrap().whole_days());
+        assert_eq!(None, "36h".parse::<Age>().unwrap().whole_days());
+        assert!("w".parse::<Age>().is_err());
+    }
+
+    #[test]
+    fn before_epoch() {
+        let civil = Civil::from_timestamp(-1);
+        assert_eq!((1969, 12, 31), (civil.year, civil.month, civil.day));
+        assert_eq!(4, civil.quarter());
+    }
+}
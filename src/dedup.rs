@@ -0,0 +1,203 @@
+//! Finding duplicate messages.
+//!
+//! Two messages are the same if they have the same `Message-ID` and the same body (compared by its
+//! SHA-256 hash), so the copies may differ in the headers added on delivery. Messages without a
+//! `Message-ID` never count as duplicates.
+//!
+//! The messages archived can be remembered in an index file (one key per line), to find the
+//! duplicates of messages archived by earlier runs.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use log::warn;
+
+use crate::select::Message;
+use crate::sha256;
+use crate::MailInfo;
+
+/// What to do with the duplicates.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Delete the duplicates of archived messages instead of archiving them again.
+    Skip,
+    /// Delete all the duplicates from the maildir.
+    Remove,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["skip", "remove"];
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "skip" => Ok(Mode::Skip),
+            "remove" => Ok(Mode::Remove),
+            _ => bail!("Unknown dedup mode {}", s),
+        }
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Mode::Skip => "skip",
+            Mode::Remove => "remove",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// The key identifying the message, from its Message-ID and the raw message.
+pub fn key(message_id: &str, raw: &[u8]) -> Option<String> {
+    let id = message_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim();
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        return None;
+    }
+    let body = (0..raw.len())
+        .find_map(|pos| {
+            let rest = &raw[pos..];
+            [&b"\n\n"[..], b"\n\r\n"]
+                .iter()
+                .find(|sep| rest.starts_with(sep))
+                .map(|sep| &rest[sep.len()..])
+        })
+        .unwrap_or_default();
+    Some(format!("{} {}", id, sha256::hex(body)))
+}
+
+/// The duplicates found during a run.
+pub struct Dedup {
+    mode: Mode,
+    /// Keys of the messages seen in the run and those in the index.
+    known: HashSet<String>,
+    /// Keys of the messages handed to the archive, by their paths.
+    pending: HashMap<PathBuf, String>,
+    /// Keys of the messages archived, to be added to the index.
+    archived: Vec<String>,
+    index: Option<PathBuf>,
+}
+
+impl Dedup {
+    /// Loads the index, if there's one.
+    pub fn new(mode: Mode, index: Option<&Path>) -> Result<Self, Error> {
+        let known = match index.map(fs::read_to_string) {
+            None => HashSet::new(),
+            Some(Ok(data)) => data.lines().map(str::to_owned).collect(),
+            Some(Err(e)) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Some(Err(e)) => {
+                let index = index.unwrap().display();
+                return Err(Error::from(e).context(format!("Failed to read {}", index)));
+            }
+        };
+        Ok(Self {
+            mode,
+            known,
+            pending: HashMap::new(),
+            archived: Vec::new(),
+            index: index.map(Path::to_owned),
+        })
+    }
+
+    /// Is the message a duplicate to be deleted?
+    ///
+    /// The `archive` tells if the message is going to be archived. Messages that are not
+    /// duplicates are remembered. Flagged messages are never duplicates to be deleted.
+    pub fn check(&mut self, mail: &MailInfo, archive: bool) -> bool {
+        let id = match mail.header("Message-ID").first() {
+            Some(id) => *id,
+            None => return false,
+        };
+        let raw = match mail.raw() {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("{:?}", e.context("Can't check for duplicates"));
+                return false;
+            }
+        };
+        let key = match key(id, &raw) {
+            Some(key) => key,
+            None => return false,
+        };
+        if self.known.contains(&key) {
+            // Flagged messages stay, even as a second copy
+            return !mail.flagged && (archive || self.mode == Mode::Remove);
+        }
+        self.known.insert(key.clone());
+        if archive {
+            self.pending.insert(mail.path.clone(), key);
+        }
+        false
+    }
+
+    /// Notes that the archive is done with the message.
+    pub fn sunk(&mut self, path: &Path, archived: bool) {
+        if let Some(key) = self.pending.remove(path) {
+            if archived {
+                self.archived.push(key);
+            } else {
+                // Still in the maildir only, a copy of it may go to the archive
+                self.known.remove(&key);
+            }
+        }
+    }
+
+    /// Notes that the message checked for archiving stays in the maildir after all.
+    pub fn kept(&mut self, path: &Path) {
+        self.pending.remove(path);
+    }
+
+    /// Adds the archived messages to the index.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let index = match &self.index {
+            Some(index) if !self.archived.is_empty() => index,
+            _ => return Ok(()),
+        };
+        let mut data = self.archived.drain(..).collect::<Vec<_>>().join("\n");
+        data.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(index)
+            .and_then(|mut file| file.write_all(data.as_bytes()))
+            .with_context(|| format!("Failed to write {}", index.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        assert_eq!(
+            Some("1@x ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()),
+            key("<1@x>", b"Subject: x\n\nabc")
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let first = b"Received: from a\nMessage-ID: <1@x>\n\nHello\n";
+        let second = b"Received: from b\r\nMessage-ID: <1@x>\r\n\r\nHello\n";
+        assert_eq!(key("<1@x>", first), key(" <1@x> ", second));
+        assert_ne!(
+            key("<1@x>", first),
+            key("<1@x>", b"Message-ID: <1@x>\n\nBye\n")
+        );
+        assert_ne!(key("<1@x>", first), key("<2@x>", first));
+        assert_eq!(key("<>", first), None);
+        assert_eq!(key("", first), None);
+    }
+}
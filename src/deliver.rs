@@ -0,0 +1,117 @@
+//! Moving messages between maildirs.
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{Context, Error};
+
+/// Makes sure the directory is a maildir, creating the `cur`, `new` and `tmp` subdirectories if
+/// needed.
+pub fn prepare(dir: &Path) -> Result<(), Error> {
+    for sub in &["cur", "new", "tmp"] {
+        let path = dir.join(sub);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Makes sure the directory entry of a file made it to the disk.
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))
+}
+
+/// Moves a message file into the `cur` folder of the `target` maildir.
+///
+/// The `id` and `flags` are used to build the new file name, so the flags survive the move. A
+/// message already there under that name is never replaced, the move fails instead. If the target
+/// is on the same filesystem, the file is linked there and the original is removed (a rename would
+/// replace an existing file). Otherwise it is copied through the `tmp` folder, synced to disk and
+/// only then the original is removed. Either way, the `cur` folder is synced before the original
+/// is removed.
+pub fn move_to(src: &Path, target: &Path, id: &str, flags: &str) -> Result<(), Error> {
+    let name = format!("{}:2,{}", id, flags);
+    let dst = target.join("cur").join(&name);
+
+    match fs::hard_link(src, &dst) {
+        Ok(()) => {
+            sync_dir(&target.join("cur"))?;
+            return fs::remove_file(src)
+                .with_context(|| format!("Failed to remove {}", src.display()));
+        }
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+        Err(e) => {
+            return Err(Error::from(e).context(format!(
+                "Failed to move {} to {}",
+                src.display(),
+                dst.display()
+            )))
+        }
+    }
+
+    let tmp = target.join("tmp").join(&name);
+    let copied = fs::copy(src, &tmp)
+        .and_then(|_| File::open(&tmp))
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Failed to copy {} to {}", src.display(), tmp.display()));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    let linked = fs::hard_link(&tmp, &dst)
+        .with_context(|| format!("Failed to move {} to {}", tmp.display(), dst.display()));
+    let _ = fs::remove_file(&tmp);
+    linked?;
+    sync_dir(&target.join("cur"))?;
+    fs::remove_file(src).with_context(|| format!("Failed to remove {}", src.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    #[test]
+    fn prepared() {
+        let dir = TempDir::new("deliver-prepare");
+        let maildir = dir.path().join("box");
+        prepare(&maildir).unwrap();
+        // Again on an existing one
+        prepare(&maildir).unwrap();
+        for sub in &["cur", "new", "tmp"] {
+            assert!(maildir.join(sub).is_dir());
+        }
+    }
+
+    #[test]
+    fn moved_with_flags() {
+        let dir = TempDir::new("deliver-move");
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        prepare(&source).unwrap();
+        prepare(&target).unwrap();
+        let src = source.join("new").join("123.abc");
+        fs::write(&src, "Subject: x\n\nbody\n").unwrap();
+        move_to(&src, &target, "123.abc", "ST").unwrap();
+        assert!(!src.exists());
+        let dst = target.join("cur").join("123.abc:2,ST");
+        assert_eq!(b"Subject: x\n\nbody\n", &fs::read(&dst).unwrap()[..]);
+    }
+
+    #[test]
+    fn existing_kept() {
+        let dir = TempDir::new("deliver-existing");
+        let maildir = dir.path().join("box");
+        prepare(&maildir).unwrap();
+        let src = dir.path().join("message");
+        fs::write(&src, "new").unwrap();
+        let dst = maildir.join("cur").join("1.x:2,S");
+        fs::write(&dst, "old").unwrap();
+        assert!(move_to(&src, &maildir, "1.x", "S").is_err());
+        assert_eq!(b"new", &fs::read(&src).unwrap()[..]);
+        assert_eq!(b"old", &fs::read(&dst).unwrap()[..]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Finding the maildir folders to process.
+//!
+//! Both the Maildir++ layout (subfolders as dot-prefixed directories like `.Lists.foo` in the
+//! root) and nested directories (`Lists/foo`) are recognized. Subfolders are named by their path
+//! relative to the root, with the components joined by dots (`Lists.foo` in both cases).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+
+/// A single maildir folder.
+#[derive(Clone, Debug)]
+pub struct Folder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Does the directory look like a maildir?
+fn is_maildir(path: &Path) -> bool {
+    path.join("cur").is_dir() && path.join("new").is_dir()
+}
+
+fn walk(dir: &Path, prefix: &str, found: &mut Vec<Folder>) -> Result<(), Error> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        // Not following symlinks, to avoid loops
+        let is_dir = entry
+            .file_type()
+            .with_context(|| format!("Failed to examine {}", entry.path().display()))?
+            .is_dir();
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !is_dir || ["cur", "new", "tmp"].contains(&file_name.as_ref()) {
+            continue;
+        }
+        let name = file_name.trim_start_matches('.');
+        if name.is_empty() {
+            continue;
+        }
+        let name = if prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        let path = entry.path();
+        if is_maildir(&path) {
+            found.push(Folder {
+                name: name.clone(),
+                path: path.clone(),
+            });
+        }
+        walk(&path, &name, found)?;
+    }
+    Ok(())
+}
+
+/// Lists the folders to process.
+///
+/// The root is named by its directory name. Its subfolders are included if `recursive` is set.
+pub fn discover(root: &Path, recursive: bool) -> Result<Vec<Folder>, Error> {
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut found = vec![Folder {
+        name,
+        path: root.to_owned(),
+    }];
+    if recursive {
+        let mut subfolders = Vec::new();
+        walk(root, "", &mut subfolders)?;
+        subfolders.sort_by(|a, b| a.name.cmp(&b.name));
+        found.extend(subfolders);
+    }
+    Ok(found)
+}
+
+/// Makes sure no two folders share a name.
+///
+/// The reports and the `{folder}` placeholder go by the name, so such folders would get mixed
+/// together (like two maildirs both called `INBOX`).
+pub fn check_names(folders: &[Folder]) -> Result<(), Error> {
+    let mut seen = HashMap::new();
+    for folder in folders {
+        if let Some(other) = seen.insert(&folder.name, &folder.path) {
+            bail!(
+                "Both {} and {} are named {}",
+                other.display(),
+                folder.path.display(),
+                folder.name
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    fn names(folders: &[Folder]) -> Vec<&str> {
+        folders.iter().map(|folder| folder.name.as_str()).collect()
+    }
+
+    #[test]
+    fn discovered() {
+        let dir = TempDir::new("folders-discover");
+        let root = dir.maildir("Mail", &[]);
+        dir.maildir("Mail/.Sent", &[]);
+        dir.maildir("Mail/.Lists.foo", &[]);
+        dir.maildir("Mail/Archive/2023", &[]);
+        // Neither a maildir, nor anything in it
+        fs::create_dir_all(root.join("notes")).unwrap();
+        fs::create_dir_all(root.join("cur/nested/cur")).unwrap();
+        fs::create_dir_all(root.join("cur/nested/new")).unwrap();
+
+        let folders = discover(&root, false).unwrap();
+        assert_eq!(vec!["Mail"], names(&folders));
+        assert_eq!(root, folders[0].path);
+
+        let folders = discover(&root, true).unwrap();
+        assert_eq!(
+            vec!["Mail", "Archive.2023", "Lists.foo", "Sent"],
+            names(&folders)
+        );
+        assert_eq!(root.join("Archive/2023"), folders[1].path);
+        assert_eq!(root.join(".Lists.foo"), folders[2].path);
+    }
+
+    #[test]
+    fn same_names() {
+        let dir = TempDir::new("folders-names");
+        let mut folders = discover(&dir.maildir("a/INBOX", &[]), false).unwrap();
+        folders.extend(discover(&dir.maildir("b/Work", &[]), false).unwrap());
+        check_names(&folders).unwrap();
+        folders.extend(discover(&dir.maildir("b/INBOX", &[]), false).unwrap());
+        assert!(check_names(&folders).is_err());
+    }
+}
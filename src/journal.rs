@@ -0,0 +1,275 @@
+//! Write-ahead journal of messages waiting for deletion.
+//!
+//! Before a batch of messages is written to the archive, the journal records the length of the
+//! archive. Once the batch is safely on the disk, the files that are going to be deleted are added
+//! with a commit mark, the files are deleted and the journal is removed. If the run is
+//! interrupted, the next one finds the journal and either finishes the deletions or cuts the
+//! archive back to where it was before the batch.
+
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Error};
+use log::info;
+
+use crate::archive::Archive;
+
+/// Content of a journal left behind by an interrupted run.
+pub struct Entry {
+    /// Length of the archive before the batch was written.
+    pub archive_len: u64,
+    /// Length of the archive after the batch was written, if it made it to the disk.
+    pub committed: Option<u64>,
+    /// Files to delete, once the batch is committed.
+    pub pending: Vec<PathBuf>,
+}
+
+/// The journal belonging to one archive.
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// The journal of the given archive file, placed next to it.
+    pub fn for_archive(archive: &Path) -> Self {
+        let mut path = archive.as_os_str().to_owned();
+        path.push(".journal");
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    fn sync_dir(&self) -> Result<(), Error> {
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))
+    }
+
+    /// Records a batch is about to be written to the archive.
+    pub fn begin(&self, archive_len: u64) -> Result<(), Error> {
+        let mut file = File::create(&self.path)
+            .with_context(|| format!("Failed to create journal {}", self.path.display()))?;
+        writeln!(file, "archive {}", archive_len)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))?;
+        self.sync_dir()
+    }
+
+    /// Records the batch is safely in the archive, together with the files to delete now.
+    pub fn commit<'a, I>(&self, archive_len: u64, pending: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let mut content = Vec::new();
+        for path in pending {
+            let path = path.as_os_str().as_bytes();
+            ensure!(
+                !path.contains(&b'\n'),
+                "Can't journal file name with newline"
+            );
+            content.extend_from_slice(b"pending ");
+            content.extend_from_slice(path);
+            content.push(b'\n');
+        }
+        content.extend_from_slice(format!("committed {}\n", archive_len).as_bytes());
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open journal {}", self.path.display()))?;
+        file.write_all(&content)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))
+    }
+
+    /// Removes the journal once the batch is fully processed.
+    pub fn clear(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove journal {}", self.path.display()))?;
+        self.sync_dir()
+    }
+
+    /// Reads the journal, if there's one.
+    pub fn load(&self) -> Result<Option<Entry>, Error> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(Error::from(e)
+                    .context(format!("Failed to read journal {}", self.path.display())))
+            }
+        };
+
+        let parse_len = |value: &[u8]| -> Result<u64, Error> {
+            let value = std::str::from_utf8(value)?;
+            Ok(value.parse()?)
+        };
+        let mut archive_len = None;
+        let mut committed = None;
+        let mut pending = Vec::new();
+        for line in content.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let space = line.iter().position(|&b| b == b' ').unwrap_or(line.len());
+            let (key, value) = (&line[..space], line.get(space + 1..).unwrap_or_default());
+            match key {
+                b"archive" => archive_len = Some(parse_len(value)?),
+                b"committed" => committed = Some(parse_len(value)?),
+                b"pending" => pending.push(PathBuf::from(OsStr::from_bytes(value))),
+                _ => bail!("Corrupt journal {}", self.path.display()),
+            }
+        }
+
+        // A crash during commit may leave some of the files without the mark, the batch is not
+        // committed then
+        if committed.is_none() {
+            pending.clear();
+        }
+        // A crash during begin may leave the journal empty. Nothing was written to the archive yet
+        // in such case.
+        match archive_len {
+            Some(archive_len) => Ok(Some(Entry {
+                archive_len,
+                committed,
+                pending,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Deals with a journal left behind by an interrupted run.
+    ///
+    /// A committed batch is finished by deleting the remaining messages, unless `rollback` is
+    /// requested. An uncommitted one (or a rolled back one) is removed from the archive.
+    pub fn recover(&self, archive: &Path, rollback: bool) -> Result<(), Error> {
+        let entry = match self.load()? {
+            Some(entry) => entry,
+            None => {
+                if self.path.exists() {
+                    self.clear()?;
+                }
+                return Ok(());
+            }
+        };
+
+        if entry.committed.is_some() && !rollback {
+            let mut deleted = 0;
+            for path in &entry.pending {
+                match fs::remove_file(path) {
+                    Ok(()) => deleted += 1,
+                    Err(e) if e.kind() == ErrorKind::NotFound => (),
+                    Err(e) => {
+                        return Err(
+                            Error::from(e).context(format!("Failed to delete {}", path.display()))
+                        )
+                    }
+                }
+            }
+            info!("Resumed interrupted run, deleted {} messages", deleted);
+        } else {
+            if let Some(missing) = entry.pending.iter().find(|p| !p.exists()) {
+                bail!(
+                    "Can't roll back, {} is already deleted and only in the archive",
+                    missing.display()
+                );
+            }
+            if archive.exists() {
+                Archive::truncate(archive, entry.archive_len)?;
+            }
+            info!("Rolled back interrupted run, the messages stay in the maildir");
+        }
+
+        self.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    /// An archive with a batch written after the first 5 bytes and two messages of the batch.
+    fn interrupted(dir: &TempDir) -> (PathBuf, Journal, Vec<PathBuf>) {
+        let archive = dir.path().join("archive.mbox");
+        fs::write(&archive, "old\n\n").unwrap();
+        let journal = Journal::for_archive(&archive);
+        journal.begin(5).unwrap();
+        fs::write(&archive, "old\n\nnew\n").unwrap();
+        let mails = vec![dir.path().join("a"), dir.path().join("b")];
+        for mail in &mails {
+            fs::write(mail, "mail").unwrap();
+        }
+        (archive, journal, mails)
+    }
+
+    #[test]
+    fn begun() {
+        let dir = TempDir::new("journal-begun");
+        let (archive, journal, mails) = interrupted(&dir);
+        let entry = journal.load().unwrap().unwrap();
+        assert_eq!(5, entry.archive_len);
+        assert_eq!(None, entry.committed);
+        assert!(entry.pending.is_empty());
+        // Not committed, the batch is cut off
+        journal.recover(&archive, false).unwrap();
+        assert_eq!(b"old\n\n", &fs::read(&archive).unwrap()[..]);
+        assert!(mails.iter().all(|mail| mail.exists()));
+        assert!(journal.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn committed() {
+        let dir = TempDir::new("journal-committed");
+        let (archive, journal, mails) = interrupted(&dir);
+        journal
+            .commit(9, mails.iter().map(PathBuf::as_path))
+            .unwrap();
+        let entry = journal.load().unwrap().unwrap();
+        assert_eq!((5, Some(9)), (entry.archive_len, entry.committed));
+        assert_eq!(mails, entry.pending);
+        // One of them is deleted already
+        fs::remove_file(&mails[0]).unwrap();
+        journal.recover(&archive, false).unwrap();
+        assert_eq!(b"old\n\nnew\n", &fs::read(&archive).unwrap()[..]);
+        assert!(!mails[1].exists());
+        assert!(journal.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn rolled_back() {
+        let dir = TempDir::new("journal-rollback");
+        let (archive, journal, mails) = interrupted(&dir);
+        journal
+            .commit(9, mails.iter().map(PathBuf::as_path))
+            .unwrap();
+        journal.recover(&archive, true).unwrap();
+        assert_eq!(b"old\n\n", &fs::read(&archive).unwrap()[..]);
+        assert!(mails.iter().all(|mail| mail.exists()));
+
+        // Too late once something was deleted
+        journal.begin(5).unwrap();
+        journal
+            .commit(9, mails.iter().map(PathBuf::as_path))
+            .unwrap();
+        fs::remove_file(&mails[0]).unwrap();
+        assert!(journal.recover(&archive, true).is_err());
+        assert!(journal.load().unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupt() {
+        let dir = TempDir::new("journal-corrupt");
+        let archive = dir.path().join("archive.mbox");
+        let journal = Journal::for_archive(&archive);
+        fs::write(dir.path().join("archive.mbox.journal"), "archive x\n").unwrap();
+        assert!(journal.load().is_err());
+        // An empty one is from a crash before anything was written
+        fs::write(dir.path().join("archive.mbox.journal"), "").unwrap();
+        journal.recover(&archive, false).unwrap();
+        assert!(!dir.path().join("archive.mbox.journal").exists());
+    }
+}
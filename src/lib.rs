@@ -0,0 +1,1608 @@
+//! Archiving or deleting old emails from maildirs.
+//!
+//! This is the library behind the `decay` command line tool, for embedding the retention logic
+//! into other programs. The stable API consists of:
+//!
+//! * [`Options`]: What to do, the same settings as the command line options. Build them with
+//!   [`Options::default`] and modify the fields, or parse them from arguments with
+//!   [`StructOpt`].
+//! * [`run`]: Does a whole run over the maildirs and returns a [`Report`] with per-folder
+//!   [`Counts`].
+//! * [`Run`]: The same step by step, for feeding it folders from elsewhere (see [`folders`] for
+//!   finding them).
+//! * [`Criteria`] and [`MailInfo`]: The message scanner and the decision what to archive, with the
+//!   [`select`] expressions.
+//! * The archive [`sink`]s, where the messages go. Custom ones can be given to
+//!   [`Run::with_sink`].
+//! * The archive formats in [`archive`] and [`mbox`], [`restore`] and [`trash`] for getting
+//!   messages back.
+//!
+//! Nothing is changed on the disk unless [`Options::confirm`] is set.
+//!
+//! ```no_run
+//! use decay::{run, Options};
+//!
+//! let options = Options {
+//!     maildir: vec!["/home/user/Mail/.Lists".into()],
+//!     archive: Some("/home/user/archive/lists-%Y.mbox.gz".into()),
+//!     age: "14d".parse().unwrap(),
+//!     confirm: true,
+//!     ..Options::default()
+//! };
+//! options.check()?;
+//! let report = run(&options)?;
+//! println!("Archived {}", report.total().archived);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Error};
+use log::{debug, error, info};
+use maildir::{MailEntry, Maildir};
+use mailparse::MailHeaderMap;
+use structopt::StructOpt;
+
+pub mod archive;
+pub mod attachment;
+pub mod date;
+pub mod dedup;
+mod deliver;
+pub mod folders;
+mod journal;
+pub mod mbox;
+pub mod newest;
+pub mod report;
+pub mod restore;
+pub mod select;
+mod sha256;
+pub mod sink;
+mod template;
+#[cfg(test)]
+mod testdir;
+mod thread;
+pub mod trash;
+mod verify;
+
+use archive::{Archive, Compression};
+use date::Age;
+use dedup::Dedup;
+use folders::Folder;
+use report::{Action, Decision, Reason, Status};
+use select::{Expr, Message};
+use sink::Sink;
+use template::Template;
+use trash::{Times, Trash};
+
+/// What to do in a run.
+///
+/// These are also the command line options of the `decay` tool.
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// The maildir to process and search for old messages.
+    ///
+    /// Can be used multiple times. Required unless running a subcommand or using --config.
+    #[structopt(short = "d", long = "dir", parse(from_os_str), number_of_values = 1)]
+    pub maildir: Vec<PathBuf>,
+
+    /// Where to put the old messages.
+    ///
+    /// May contain placeholders expanded for each message, to split the messages into multiple
+    /// archives: %Y, %y, %m, %d and %q (quarter) from the date of the message, {folder} for the
+    /// name of the maildir and %% for a literal % (any other % is kept as it is).
+    #[structopt(short = "a", long = "archive", parse(from_os_str))]
+    pub archive: Option<PathBuf>,
+
+    /// Move the old messages into this maildir instead of an mbox file.
+    #[structopt(long = "archive-maildir", parse(from_os_str))]
+    pub archive_maildir: Option<PathBuf>,
+
+    /// Save the old messages as separate .eml files into this directory instead of an mbox file.
+    ///
+    /// May contain the same placeholders as --archive, to split the messages into subdirectories.
+    #[structopt(long = "archive-eml", parse(from_os_str))]
+    pub archive_eml: Option<PathBuf>,
+
+    /// Move messages that can't be parsed into this maildir.
+    ///
+    /// They are moved once their file is old enough (by the modification time) by --age, --before
+    /// and --after.
+    #[structopt(long = "quarantine", parse(from_os_str))]
+    pub quarantine: Option<PathBuf>,
+
+    /// Write the list of messages that couldn't be parsed, with the errors, to this file.
+    ///
+    /// Each line has the path of the message, what happened to it and the error, separated by
+    /// tabs.
+    #[structopt(long = "parse-report", parse(from_os_str))]
+    pub parse_report: Option<PathBuf>,
+
+    /// How to compress the archive.
+    ///
+    /// Guessed from the extension of the archive (.gz, .xz, .zst, .bz2) if not set. Gzip is built
+    /// in, the others run the `xz`, `zstd` or `bzip2` program, which has to be in PATH.
+    #[structopt(long = "compression", possible_values = Compression::NAMES)]
+    pub compression: Option<Compression>,
+
+    /// Compression level, the default of the compression if not set.
+    #[structopt(long = "compression-level")]
+    pub compression_level: Option<u32>,
+
+    /// The mbox dialect to write the archive in.
+    #[structopt(
+        long = "mbox-format",
+        default_value = "mboxo",
+        possible_values = mbox::Format::NAMES
+    )]
+    pub mbox_format: mbox::Format,
+
+    /// Rewrite a compressed archive into a single compression stream instead of appending a new
+    /// one.
+    ///
+    /// Some tools read only the first stream of a file. The messages are appended and deleted in
+    /// batches, and at the end of the run the whole archive is decompressed and compressed again
+    /// into a temporary file, which then replaces the archive.
+    #[structopt(long = "single-stream")]
+    pub single_stream: bool,
+
+    /// Delete messages only once they are safely in the archive.
+    ///
+    /// The messages are written in batches, each synced to the disk before the messages are
+    /// deleted. A journal next to the archive allows an interrupted run to be finished (or rolled
+    /// back) by the next one.
+    #[structopt(short = "j", long = "journal")]
+    pub journal: bool,
+
+    /// Number of messages in one batch with --journal, --verify or --single-stream.
+    #[structopt(long = "batch-size", default_value = "100")]
+    pub batch_size: usize,
+
+    /// Re-read each batch from the archive and check it before deleting the messages.
+    #[structopt(long = "verify")]
+    pub verify: bool,
+
+    /// Roll back a batch interrupted after it made it to the archive, instead of finishing it.
+    #[structopt(long = "rollback")]
+    pub rollback: bool,
+
+    /// Remove messages instead of archiving.
+    #[structopt(short = "r", long = "remove")]
+    pub remove: bool,
+
+    /// With --remove, move the messages into this trash maildir instead of deleting them.
+    ///
+    /// The duplicates found by --dedup go there too.
+    ///
+    /// Messages in the trash for longer than --grace are deleted by later runs. The undo
+    /// subcommand puts them back.
+    #[structopt(long = "trash", parse(from_os_str))]
+    pub trash: Option<PathBuf>,
+
+    /// With --remove, only mark the messages with the T (trashed) flag.
+    ///
+    /// The duplicates found by --dedup are marked too.
+    ///
+    /// Messages with the flag for longer than --grace are deleted by later runs. The times are kept
+    /// in decay-trashed.log in the maildir. Messages marked by other programs are deleted too, the
+    /// grace starts when a run first finds them.
+    #[structopt(long = "trash-flag")]
+    pub trash_flag: bool,
+
+    /// How long to keep trashed messages before deleting them for good (same format as --age).
+    #[structopt(long = "grace", default_value = "7d")]
+    pub grace: Age,
+
+    /// Don't do a dry run only, actually run the actions.
+    #[structopt(short = "c", long = "confirm")]
+    pub confirm: bool,
+
+    /// Process the subfolders too.
+    ///
+    /// Both Maildir++ (.Lists.foo) and nested (Lists/foo) subfolders are found. Use {folder} in
+    /// the archive path to give each folder its own archive. The folder names (the maildirs are
+    /// named by their directory names) have to be unique across all the maildirs.
+    #[structopt(short = "R", long = "recursive")]
+    pub recursive: bool,
+
+    /// Archive whole threads only, once their newest message is old enough.
+    ///
+    /// The threads are built from the Message-ID, In-Reply-To and References headers of the
+    /// messages in each folder, including the ones in new. A message is archived only if it would
+    /// be archived on its own and the newest message of its thread is older than --age (or from
+    /// before --before).
+    #[structopt(long = "threads")]
+    pub threads: bool,
+
+    /// With --threads, keep the whole thread if any of its messages is flagged.
+    #[structopt(long = "keep-flagged-threads")]
+    pub keep_flagged_threads: bool,
+
+    /// Always keep this many of the newest messages in each folder (or group by --keep-by).
+    ///
+    /// Only the older messages are archived, if they are chosen by the other rules too. Use with
+    /// --age 0 to go by the count only. The messages in new count too.
+    #[structopt(long = "keep-newest")]
+    pub keep_newest: Option<usize>,
+
+    /// What --keep-newest counts by: folder, from (the sender address) or list-id.
+    ///
+    /// With from or list-id, the newest messages are kept for each sender or list separately.
+    /// Messages without the header are not limited by the count.
+    #[structopt(
+        long = "keep-by",
+        default_value = "folder",
+        possible_values = newest::Group::NAMES
+    )]
+    pub keep_by: newest::Group,
+
+    /// Archive only as many messages as needed to fit each maildir into this size.
+    ///
+    /// In bytes or with a K, M or G suffix, like 2G. The quota is for the whole maildir, including
+    /// its subfolders with --recursive. The oldest of the messages chosen by the other rules (from
+    /// any of the folders) are archived until the rest (including the messages in new) fits.
+    /// Messages purged, deduplicated or quarantined in the same run no longer count. Use with
+    /// --age 0 to go by the size only.
+    #[structopt(long = "max-size", parse(try_from_str = select::parse_size))]
+    pub max_size: Option<u64>,
+
+    /// Find duplicates by the Message-ID and the SHA-256 hash of the body.
+    ///
+    /// With skip, messages to be archived that are a duplicate of a message archived before (in
+    /// this run or by the --dedup-index) or one still in the maildir are deleted instead of being
+    /// archived again. With remove, all the duplicates are deleted from the maildirs, only the
+    /// first copy found stays. Flagged messages are never deleted as duplicates, and with --trash
+    /// or --trash-flag the duplicates are trashed instead of deleted.
+    #[structopt(long = "dedup", possible_values = dedup::Mode::NAMES)]
+    pub dedup: Option<dedup::Mode>,
+
+    /// Remember the archived messages in this file, to find duplicates of messages archived by
+    /// earlier runs.
+    ///
+    /// Only messages archived with the index in use are in it.
+    #[structopt(long = "dedup-index", parse(from_os_str))]
+    pub dedup_index: Option<PathBuf>,
+
+    /// What to do with big attachments of the archived messages: keep, strip or extract.
+    ///
+    /// With strip, attachments bigger than --attachment-size are replaced by a short text part
+    /// with their name, type, size and SHA-256 hash. With extract, they are also saved under their
+    /// hash into a directory next to the archive (archive.attachments for an mbox, attachments in
+    /// the --archive-eml directory). The headers and the text of the message are kept as they are
+    /// and each replaced attachment is listed in an X-Decay-Stripped header.
+    #[structopt(
+        long = "attachments",
+        default_value = "keep",
+        possible_values = attachment::Policy::NAMES
+    )]
+    pub attachments: attachment::Policy,
+
+    /// Attachments bigger than this are stripped or extracted (with a K, M or G suffix).
+    #[structopt(
+        long = "attachment-size",
+        default_value = "1M",
+        parse(try_from_str = select::parse_size)
+    )]
+    pub attachment_size: u64,
+
+    /// Process "new" old emails too.
+    #[structopt(short = "n", long = "new")]
+    pub new: bool,
+
+    /// How old messages to archive.
+    ///
+    /// In days, or with a unit: s, min, h, d, w, mo (calendar months) or y (calendar years), like
+    /// 36h, 6w or 18mo.
+    #[structopt(short = "A", long = "age", default_value = "30")]
+    pub age: Age,
+
+    /// Where to take the date of a message from.
+    ///
+    /// A comma separated list of header (the Date header), received (the newest Received header),
+    /// filename (the delivery time in the maildir file name) and mtime (modification time of the
+    /// file). They are tried in order until one works, so with `header,mtime` messages with a
+    /// broken Date header are still archived instead of being counted as parse errors.
+    #[structopt(
+        long = "date-source",
+        default_value = "header",
+        use_delimiter = true,
+        possible_values = date::Source::NAMES
+    )]
+    pub date_source: Vec<date::Source>,
+
+    /// Archive messages from before this date instead of the ones older than --age.
+    ///
+    /// As YYYY-MM-DD, YYYY-MM-DDTHH:MM or YYYY-MM-DDTHH:MM:SS, in UTC. Unlike --age, the result
+    /// doesn't depend on when the run happens.
+    #[structopt(long = "before", parse(try_from_str = date::parse))]
+    pub before: Option<i64>,
+
+    /// Archive only messages from this date on (same format as --before).
+    #[structopt(long = "after", parse(try_from_str = date::parse))]
+    pub after: Option<i64>,
+
+    /// Archive the messages matching this expression, instead of the ones older than --age.
+    ///
+    /// Conditions like `from ~ "regex"` (any header, case insensitive), `size > 1M`, `age > 14`
+    /// (in whole days, or exact with a unit like 36h), `date < 2024-01-01`, `seen`, `flagged`,
+    /// `replied`, `passed`, `draft`, `trashed`, `new` and `attachment` can be combined with `and`,
+    /// `or`, `not` and parentheses. The default is `age >= 30 and seen and not flagged`. Messages
+    /// in new are still looked at only with --new, and --before and --after still apply.
+    #[structopt(long = "select")]
+    pub select: Option<Expr>,
+
+    /// Also print a report to the standard output in this format.
+    ///
+    /// With json, each run (job) prints one line with the counts per folder and per reason, and
+    /// what was done with each message and why.
+    #[structopt(
+        long = "report",
+        default_value = "text",
+        possible_values = report::Format::NAMES
+    )]
+    pub report: report::Format,
+}
+
+impl Default for Options {
+    /// The same defaults as on the command line, with no maildir and no action set.
+    fn default() -> Self {
+        Self {
+            maildir: Vec::new(),
+            archive: None,
+            archive_maildir: None,
+            archive_eml: None,
+            quarantine: None,
+            parse_report: None,
+            compression: None,
+            compression_level: None,
+            mbox_format: mbox::Format::Mboxo,
+            single_stream: false,
+            journal: false,
+            batch_size: 100,
+            verify: false,
+            rollback: false,
+            remove: false,
+            trash: None,
+            trash_flag: false,
+            grace: Age::Seconds(7 * 86_400),
+            confirm: false,
+            recursive: false,
+            threads: false,
+            keep_flagged_threads: false,
+            keep_newest: None,
+            keep_by: newest::Group::Folder,
+            max_size: None,
+            dedup: None,
+            dedup_index: None,
+            attachments: attachment::Policy::Keep,
+            attachment_size: 1024 * 1024,
+            new: false,
+            age: Age::Seconds(30 * 86_400),
+            date_source: vec![date::Source::Header],
+            before: None,
+            after: None,
+            select: None,
+            report: report::Format::Text,
+        }
+    }
+}
+
+impl Options {
+    /// Checks the options make sense, before anything is done.
+    pub fn check(&self) -> Result<(), Error> {
+        ensure!(!self.maildir.is_empty(), "Maildir not set");
+        for maildir in &self.maildir {
+            ensure!(
+                maildir.is_dir(),
+                "Maildir {} does not exist",
+                maildir.display()
+            );
+        }
+        let actions = [
+            self.archive.is_some(),
+            self.archive_maildir.is_some(),
+            self.archive_eml.is_some(),
+            self.remove,
+        ];
+        ensure!(
+            actions.iter().filter(|&&a| a).count() == 1,
+            "Exactly one of archive, archive to maildir, archive to eml or remove must be chosen"
+        );
+        ensure!(
+            !self.journal || self.archive.is_some(),
+            "Journal can be used only when archiving to an mbox"
+        );
+        ensure!(
+            !self.verify || self.archive.is_some(),
+            "Verification can be used only when archiving to an mbox"
+        );
+        ensure!(
+            (self.trash.is_none() && !self.trash_flag) || self.remove || self.dedup.is_some(),
+            "Trash can be used only when removing or deduplicating"
+        );
+        ensure!(
+            self.trash.is_none() || !self.trash_flag,
+            "Can't use both trash maildir and trash flag"
+        );
+        ensure!(
+            !self.keep_flagged_threads || self.threads,
+            "Keeping flagged threads can be used only with threads"
+        );
+        ensure!(
+            self.dedup_index.is_none() || self.dedup.is_some(),
+            "Dedup index can be used only with dedup"
+        );
+        ensure!(
+            self.attachments == attachment::Policy::Keep
+                || self.archive.is_some()
+                || self.archive_eml.is_some(),
+            "Attachments can be stripped only when archiving to an mbox or eml files"
+        );
+        ensure!(self.batch_size > 0, "Batch size must be positive");
+        ensure!(!self.date_source.is_empty(), "No date source");
+        if let (Some(before), Some(after)) = (self.before, self.after) {
+            ensure!(
+                after < before,
+                "The --after date must be before the --before one"
+            );
+        }
+        if let Some(archive) = &self.archive {
+            let compression = self.compression(archive);
+            compression.check_level(self.compression_level)?;
+            compression.check_program()?;
+            // Parents of templated archives are created on demand
+            if !Template::parse(archive).is_templated() {
+                check_parent(archive)?;
+            }
+        }
+        if let Some(target) = &self.archive_maildir {
+            check_parent(target)?;
+        }
+        if let Some(dir) = &self.archive_eml {
+            if !Template::parse(dir).is_templated() {
+                check_parent(dir)?;
+            }
+        }
+        if let Some(target) = &self.quarantine {
+            check_parent(target)?;
+        }
+        if let Some(trash) = &self.trash {
+            check_parent(trash)?;
+        }
+        if let Some(report) = &self.parse_report {
+            check_parent(report)?;
+        }
+        if let Some(index) = &self.dedup_index {
+            check_parent(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Is the whole folder needed to decide about its messages?
+    fn whole_folder(&self) -> bool {
+        self.threads || self.keep_newest.is_some() || self.max_size.is_some()
+    }
+
+    /// Are the messages archived in batches?
+    fn batched(&self) -> bool {
+        self.journal || self.verify || self.single_stream
+    }
+
+    /// Is the batch big enough to be written?
+    fn batch_full(&self, len: usize) -> bool {
+        len >= self.batch_size
+    }
+
+    /// The compression of the archive, either set or guessed from the file name.
+    pub fn compression(&self, archive: &Path) -> Compression {
+        self.compression
+            .unwrap_or_else(|| Compression::from_path(archive))
+    }
+}
+
+/// Makes sure the directory the path lives in exists.
+fn check_parent(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent().filter(|p| *p != Path::new("")) {
+        ensure!(
+            parent.is_dir(),
+            "Directory {} does not exist",
+            parent.display()
+        );
+    }
+    Ok(())
+}
+
+/// Statistics of one folder.
+#[derive(Clone, Debug, Default)]
+pub struct Counts {
+    /// Messages archived (or removed).
+    pub archived: usize,
+    /// Messages left in place.
+    pub kept: usize,
+    /// Messages that couldn't be parsed.
+    pub parse_err: usize,
+    /// Messages that failed to be archived.
+    pub move_err: usize,
+    /// Unparseable messages moved to the quarantine (also counted in parse_err).
+    pub quarantined: usize,
+    /// Trashed messages deleted after their grace period.
+    pub purged: usize,
+    /// Duplicate messages deleted.
+    pub duplicates: usize,
+    /// Messages that would be archived, quarantined, purged or deleted as duplicates if this
+    /// wasn't a dry run.
+    pub pending: usize,
+    /// Bytes of messages archived, quarantined, purged or deleted as duplicates (or that would be,
+    /// in a dry run).
+    pub freed: u64,
+    /// The number of messages decided for each of the reasons.
+    pub reasons: BTreeMap<Reason, usize>,
+}
+
+impl Counts {
+    /// Counts a message.
+    fn record(&mut self, decision: &Decision) {
+        let (action, reason) = (decision.action, decision.reason);
+        *self.reasons.entry(reason).or_default() += 1;
+        if reason == Reason::Unparseable {
+            self.parse_err += 1;
+        }
+        if action.frees() {
+            self.freed += decision.size;
+        }
+        match action {
+            Action::Archived => self.archived += 1,
+            Action::Failed => self.move_err += 1,
+            // Unparseable messages are counted only as parse errors
+            Action::Kept if reason != Reason::Unparseable => self.kept += 1,
+            Action::Kept => (),
+            Action::Quarantined => self.quarantined += 1,
+            Action::Purged => self.purged += 1,
+            Action::Deduplicated => self.duplicates += 1,
+            Action::WouldArchive
+            | Action::WouldQuarantine
+            | Action::WouldPurge
+            | Action::WouldDeduplicate => self.pending += 1,
+        }
+    }
+
+    /// Adds the other statistics to these.
+    pub fn add(&mut self, other: &Counts) {
+        self.archived += other.archived;
+        self.kept += other.kept;
+        self.parse_err += other.parse_err;
+        self.move_err += other.move_err;
+        self.quarantined += other.quarantined;
+        self.purged += other.purged;
+        self.duplicates += other.duplicates;
+        self.pending += other.pending;
+        self.freed += other.freed;
+        for (reason, count) in &other.reasons {
+            *self.reasons.entry(*reason).or_default() += count;
+        }
+    }
+}
+
+/// The current time, as unix timestamp.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time before epoch")
+        .as_secs() as i64
+}
+
+/// Decides which messages to archive.
+pub struct Criteria {
+    now: i64,
+    /// Messages up to this time are old enough, by --age.
+    cutoff: i64,
+    before: Option<i64>,
+    after: Option<i64>,
+    must_seen: bool,
+    select: Option<Expr>,
+}
+
+impl Criteria {
+    /// The criteria given by the options, relative to the current time.
+    pub fn new(opts: &Options) -> Self {
+        let now = now();
+        Self {
+            now,
+            cutoff: opts.age.before(now),
+            before: opts.before,
+            after: opts.after,
+            must_seen: !opts.new,
+            select: opts.select.clone(),
+        }
+    }
+
+    /// Should the message be archived?
+    pub fn should_archive(&self, mail: &MailInfo) -> bool {
+        self.decide(mail).0
+    }
+
+    /// Should the message be archived, and why (not)?
+    pub fn decide(&self, mail: &MailInfo) -> (bool, Reason) {
+        let date = mail.date_resolved;
+        let in_range = self.before.map_or(true, |before| date < before)
+            && self.after.map_or(true, |after| date >= after);
+        if !in_range {
+            return (false, Reason::OutOfRange);
+        }
+        if let Some(select) = &self.select {
+            return match select.matches(mail, self.now) {
+                true => (true, Reason::Selected),
+                false => (false, Reason::NotSelected),
+            };
+        }
+        if mail.flagged {
+            (false, Reason::Flagged)
+        } else if !self.old(date) {
+            (false, Reason::TooNew)
+        } else if self.must_seen && !mail.seen {
+            (false, Reason::Unseen)
+        } else {
+            (true, Reason::Old)
+        }
+    }
+
+    /// Is the time old enough by --age, --before and --after?
+    pub fn old(&self, date: i64) -> bool {
+        match self.before {
+            Some(before) => date < before && self.after.map_or(true, |after| date >= after),
+            None => date <= self.cutoff && self.after.map_or(true, |after| date >= after),
+        }
+    }
+}
+
+/// A message found in a maildir.
+pub struct MailInfo {
+    pub subject: String,
+    /// The Date header, as it is.
+    pub date: String,
+    /// The date of the message (by the date sources), as unix timestamp.
+    pub date_resolved: i64,
+    /// The maildir ID (the file name without the flags).
+    pub id: String,
+    /// Name of the folder the message is in.
+    pub folder: String,
+    pub path: PathBuf,
+    /// The maildir flags.
+    pub flags: String,
+    seen: bool,
+    flagged: bool,
+    /// Is it in the new subdirectory?
+    new: bool,
+    size: u64,
+    attachment: bool,
+    headers: Vec<(String, String)>,
+}
+
+/// The modification time of the file, as unix timestamp.
+fn mtime(path: &Path) -> Result<i64, Error> {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map(|time| match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        })
+        .with_context(|| format!("Failed to read the modification time of {}", path.display()))
+}
+
+/// Finds the date of the message, trying the sources in order.
+fn resolve_date(mail: &mut MailEntry, sources: &[date::Source]) -> Result<i64, Error> {
+    let mut failed = None;
+    for source in sources {
+        let date = match source {
+            date::Source::Header => mail
+                .date()
+                .map_err(Error::from)
+                .and_then(date::known)
+                .context("Broken Date header"),
+            date::Source::Received => mail
+                .received()
+                .map_err(Error::from)
+                .and_then(date::known)
+                .context("Broken Received header"),
+            date::Source::Filename => mail
+                .id()
+                .split('.')
+                .next()
+                .and_then(|time| time.parse().ok())
+                .context("No delivery time in the file name"),
+            date::Source::Mtime => mtime(mail.path()),
+        };
+        match date {
+            Ok(date) => return Ok(date),
+            Err(e) => {
+                debug!("No date from {} for {}: {}", source, mail.id(), e);
+                failed = Some(e);
+            }
+        }
+    }
+    Err(failed.expect("No date source"))
+}
+
+impl MailInfo {
+    /// Reads the message, taking the date from the first of the `sources` that works.
+    pub fn new(
+        mail: &mut MailEntry,
+        folder: &str,
+        sources: &[date::Source],
+    ) -> Result<Self, Error> {
+        let seen = mail.is_seen();
+        let flagged = mail.is_flagged();
+        let date_resolved = resolve_date(mail, sources)?;
+        let size = fs::metadata(mail.path())
+            .with_context(|| format!("Failed to examine {}", mail.path().display()))?
+            .len();
+        let new = mail.path().parent().and_then(Path::file_name) == Some("new".as_ref());
+        let parsed = mail.parsed().context("Can't parse mail")?;
+        let attachment = select::has_attachment(&parsed);
+        let headers = parsed.get_headers();
+        let date = headers.get_first_value("Date").unwrap_or_default();
+        let subject = headers.get_first_value("Subject").unwrap_or_default();
+        let headers = headers
+            .into_iter()
+            .map(|h| (h.get_key(), h.get_value()))
+            .collect();
+        Ok(Self {
+            subject,
+            date,
+            date_resolved,
+            id: mail.id().to_owned(),
+            folder: folder.to_owned(),
+            path: mail.path().to_owned(),
+            flags: mail.flags().to_owned(),
+            seen,
+            flagged,
+            new,
+            size,
+            attachment,
+            headers,
+        })
+    }
+
+    /// The record of what was done with the message.
+    fn decision(self, action: Action, reason: Reason) -> Decision {
+        Decision {
+            folder: self.folder,
+            path: self.path,
+            size: self.size,
+            action,
+            reason,
+        }
+    }
+
+    /// Reads the whole message.
+    pub fn raw(&self) -> Result<Vec<u8>, Error> {
+        fs::read(&self.path).with_context(|| format!("Failed to read {}", self.path.display()))
+    }
+
+    /// Writes the message in the mbox format.
+    pub fn archive(&self, dest: &mut dyn Write, format: mbox::Format) -> Result<(), Error> {
+        let data = self.raw()?;
+        mbox::write(dest, &data, &self.flags, SystemTime::now(), format)
+            .context("Failed to output email")?;
+
+        Ok(())
+    }
+
+    fn move_to(&self, target: &Path) -> Result<(), Error> {
+        deliver::move_to(&self.path, target, &self.id, &self.flags)
+    }
+
+    /// Removes the message from the maildir.
+    pub fn delete(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path).with_context(|| format!("Failed to delete mail {}", self))
+    }
+}
+
+impl Message for MailInfo {
+    fn header(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn date(&self) -> i64 {
+        self.date_resolved
+    }
+
+    fn flag(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+
+    fn is_new(&self) -> bool {
+        self.new
+    }
+
+    fn has_attachment(&self) -> bool {
+        self.attachment
+    }
+}
+
+impl Display for MailInfo {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}/{}/{}", self.id, self.date, self.subject)
+    }
+}
+
+/// Rewrites an archive into a single compression stream.
+pub fn compact(opts: &Options, path: &Path) -> Result<(), Error> {
+    ensure!(path.is_file(), "Archive {} does not exist", path.display());
+    let compression = opts.compression(path);
+    compression.check_level(opts.compression_level)?;
+    compression.check_program()?;
+    let before = fs::metadata(path)
+        .with_context(|| format!("Failed to examine {}", path.display()))?
+        .len();
+    let after = Archive::rewrite(path, compression, opts.compression_level)?.finish()?;
+    info!(
+        "Compacted {}: {} -> {} bytes",
+        path.display(),
+        before,
+        after
+    );
+    Ok(())
+}
+
+/// Records a message the sink is done with.
+fn sunk(
+    report: &mut Report,
+    reasons: &mut HashMap<PathBuf, Reason>,
+    dedup: &mut Option<Dedup>,
+    mail: MailInfo,
+    archived: bool,
+) {
+    if let Some(dedup) = dedup {
+        dedup.sunk(&mail.path, archived);
+    }
+    let action = if archived {
+        Action::Archived
+    } else {
+        Action::Failed
+    };
+    // Only the messages handed to the sink come back from it
+    let reason = reasons.remove(&mail.path).unwrap_or(Reason::Old);
+    report.record(mail.decision(action, reason));
+}
+
+/// The messages of a folder waiting for a decision about all of them.
+#[derive(Default)]
+struct Held {
+    mails: Vec<MailInfo>,
+    /// Which of the messages may be archived, the rest are only looked at.
+    eligible: Vec<bool>,
+    /// Of all the files in the folder, less what was already removed from it.
+    size: u64,
+}
+
+/// One run over the maildir folders.
+///
+/// Created by [`Run::new`], fed the folders by [`Run::process`] (or [`Run::process_mailbox`])
+/// and completed by [`Run::finish`].
+/// The [`run`] function does all that for the maildirs in the options.
+pub struct Run<'a> {
+    opts: &'a Options,
+    criteria: Criteria,
+    sink: Box<dyn Sink + 'a>,
+    dedup: Option<Dedup>,
+    /// Where the duplicates go, the trash or the T flag as set by the options.
+    duplicates: sink::Remove,
+    /// The trash times of the folder being processed, with --trash-flag.
+    times: Option<Times>,
+    /// Why the messages in the sink were archived, until it reports them done.
+    reasons: HashMap<PathBuf, Reason>,
+    report: Report,
+    parse_report: Option<BufWriter<File>>,
+}
+
+impl<'a> Run<'a> {
+    /// A run archiving into the destination set in the options.
+    ///
+    /// Batches of an interrupted run left in the mbox archives are finished (or rolled back) here,
+    /// before any folder is processed.
+    pub fn new(opts: &'a Options) -> Result<Self, Error> {
+        let criteria = Criteria::new(opts);
+        let sink = sink::from_options(opts, criteria.now)?;
+        Self::with_sink(opts, criteria, sink)
+    }
+
+    /// A run archiving into a custom sink.
+    ///
+    /// The destination and removal options ([`Options::archive`], [`Options::remove`] and the
+    /// like) are ignored, the sink decides what happens to the messages. Only the duplicates found
+    /// by [`Options::dedup`] still go to the [`Options::trash`] (or get the
+    /// [`Options::trash_flag`]).
+    pub fn with_sink(
+        opts: &'a Options,
+        criteria: Criteria,
+        sink: Box<dyn Sink + 'a>,
+    ) -> Result<Self, Error> {
+        let dedup = opts
+            .dedup
+            .map(|mode| Dedup::new(mode, opts.dedup_index.as_deref()))
+            .transpose()?;
+        let duplicates = sink::Remove::new(opts, criteria.now);
+        Ok(Self {
+            opts,
+            criteria,
+            sink,
+            dedup,
+            duplicates,
+            times: None,
+            reasons: HashMap::new(),
+            report: Report {
+                decisions: (opts.report == report::Format::Json).then(Vec::new),
+                ..Report::default()
+            },
+            parse_report: None,
+        })
+    }
+
+    /// Archives (or deletes) a single message.
+    ///
+    /// Only errors that should stop the whole run are returned, others are just counted.
+    fn archive(&mut self, mail: MailInfo, reason: Reason) -> Result<(), Error> {
+        self.reasons.insert(mail.path.clone(), reason);
+        let (report, reasons, dedup) = (&mut self.report, &mut self.reasons, &mut self.dedup);
+        self.sink.put(mail, &mut |mail, archived| {
+            sunk(report, reasons, dedup, mail, archived)
+        })
+    }
+
+    /// Goes through the messages of one folder, archiving the ones selected.
+    ///
+    /// Errors of individual messages are only counted, an error is returned only if the whole run
+    /// needs to stop.
+    pub fn process(&mut self, folder: &Folder) -> Result<(), Error> {
+        let held = self.collect(folder)?;
+        self.settle(vec![held])
+    }
+
+    /// Goes through the folders of one mailbox (a maildir with its subfolders).
+    ///
+    /// Same as [`process`](Self::process) on each of the folders, except that the
+    /// [`Options::max_size`] quota is for all of them together.
+    pub fn process_mailbox(&mut self, folders: &[Folder]) -> Result<(), Error> {
+        if self.opts.max_size.is_none() {
+            return folders.iter().try_for_each(|folder| self.process(folder));
+        }
+        let held = folders
+            .iter()
+            .map(|folder| self.collect(folder))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.settle(held)
+    }
+
+    /// Lists the messages of a folder.
+    ///
+    /// The messages that can be decided about on their own are handled right away, the rest are
+    /// returned.
+    fn collect(&mut self, folder: &Folder) -> Result<Held, Error> {
+        let dir = Maildir::from(folder.path.clone());
+        let whole = self.opts.whole_folder();
+        let mails = dir.list_cur().map(|mail| (mail, false));
+        // The messages in new still tie the threads together and count as the newest, even
+        // without --new
+        let mails = if self.opts.new || whole {
+            let new = dir.list_new().map(|mail| (mail, true));
+            Box::new(mails.chain(new)) as Box<dyn Iterator<Item = _>>
+        } else {
+            Box::new(mails)
+        };
+        // Make sure even folders with nothing in them show up in the summary
+        self.report.folders.entry(folder.name.clone()).or_default();
+        if self.opts.trash_flag {
+            self.times = Some(Times::load(&folder.path)?);
+        }
+        let mut held = Held::default();
+
+        for (mail, in_new) in mails {
+            // Looked at only for the decisions about the others
+            let context = in_new && !self.opts.new;
+            let mut entry = match mail {
+                Ok(entry) => entry,
+                Err(e) if context => {
+                    debug!("Failed to list email: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    error!("{:?}", Error::from(e).context("Failed to list email"));
+                    self.report
+                        .folders
+                        .entry(folder.name.clone())
+                        .or_default()
+                        .parse_err += 1;
+                    continue;
+                }
+            };
+            if let Ok(meta) = fs::metadata(entry.path()) {
+                held.size += meta.len();
+            }
+            let mail = MailInfo::new(&mut entry, &folder.name, &self.opts.date_source)
+                .with_context(|| format!("Failed to parse email {}", entry.id()));
+
+            let freed = match mail {
+                Ok(mail) if context => {
+                    held.mails.push(mail);
+                    held.eligible.push(false);
+                    0
+                }
+                Err(e) if context => {
+                    debug!("{:?}", e);
+                    0
+                }
+                Ok(mail) if self.times.is_some() && mail.flags.contains('T') => self.purge(mail)?,
+                Ok(mail) if whole => {
+                    held.mails.push(mail);
+                    held.eligible.push(true);
+                    0
+                }
+                Ok(mail) => {
+                    let decision = self.criteria.decide(&mail);
+                    self.handle(mail, decision)?;
+                    0
+                }
+                Err(e) => self.unparseable(&entry, &folder.name, e)?,
+            };
+            held.size = held.size.saturating_sub(freed);
+        }
+
+        match self.times.take() {
+            Some(times) if self.opts.confirm => times.save()?,
+            _ => (),
+        }
+        Ok(held)
+    }
+
+    /// Decides about the held messages and handles them.
+    ///
+    /// The folders are planned each on its own, the quota is for all of them together.
+    fn settle(&mut self, folders: Vec<Held>) -> Result<(), Error> {
+        let mut size = 0;
+        let mut planned = Vec::new();
+        for held in folders {
+            let decisions = self.plan(&held.mails);
+            size += held.size;
+            let mails = held.mails.into_iter().zip(held.eligible).zip(decisions);
+            planned.extend(
+                mails
+                    .filter(|((_, eligible), _)| *eligible)
+                    .map(|((mail, _), d)| (mail, d)),
+            );
+        }
+        let max_size = match self.opts.max_size {
+            Some(max_size) => max_size,
+            None => {
+                for (mail, decision) in planned {
+                    self.handle(mail, decision)?;
+                }
+                return Ok(());
+            }
+        };
+
+        // The duplicates go first, the space they take doesn't count against the quota
+        let mut remaining = Vec::new();
+        for (mail, decision) in planned {
+            if self.duplicate(&mail, decision.0) {
+                size = size.saturating_sub(self.deduplicate(mail)?);
+            } else {
+                remaining.push((mail, decision));
+            }
+        }
+        let mut chosen = (0..remaining.len())
+            .filter(|&idx| remaining[idx].1 .0)
+            .collect::<Vec<_>>();
+        chosen.sort_by_key(|&idx| remaining[idx].0.date_resolved);
+        for idx in chosen {
+            if size <= max_size {
+                remaining[idx].1 = (false, Reason::WithinQuota);
+                if let Some(dedup) = &mut self.dedup {
+                    dedup.kept(&remaining[idx].0.path);
+                }
+            } else {
+                size = size.saturating_sub(remaining[idx].0.size);
+            }
+        }
+        for (mail, decision) in remaining {
+            self.act(mail, decision)?;
+        }
+        Ok(())
+    }
+
+    /// Decides about the messages of a whole folder at once.
+    ///
+    /// The [`Options::max_size`] quota is left for [`settle`](Self::settle).
+    fn plan(&self, mails: &[MailInfo]) -> Vec<(bool, Reason)> {
+        let mut decisions = mails
+            .iter()
+            .map(|mail| self.criteria.decide(mail))
+            .collect::<Vec<_>>();
+        if self.opts.threads {
+            let threads = thread::group(mails);
+            let mut newest = HashMap::new();
+            let mut flagged = HashSet::new();
+            for (mail, thread) in mails.iter().zip(&threads) {
+                let date = newest.entry(thread).or_insert(mail.date_resolved);
+                *date = (*date).max(mail.date_resolved);
+                if mail.flagged {
+                    flagged.insert(thread);
+                }
+            }
+            for (decision, thread) in decisions.iter_mut().zip(&threads) {
+                if !decision.0 {
+                    continue;
+                }
+                if !self.criteria.old(newest[thread]) {
+                    *decision = (false, Reason::ThreadActive);
+                } else if self.opts.keep_flagged_threads && flagged.contains(thread) {
+                    *decision = (false, Reason::ThreadFlagged);
+                }
+            }
+        }
+        if let Some(keep) = self.opts.keep_newest {
+            let newest = newest::newest(mails, self.opts.keep_by, keep);
+            for (decision, newest) in decisions.iter_mut().zip(newest) {
+                if decision.0 && newest {
+                    *decision = (false, Reason::Newest);
+                }
+            }
+        }
+        decisions
+    }
+
+    /// Archives the message or leaves it be, as decided, unless it's a duplicate.
+    fn handle(&mut self, mail: MailInfo, decision: (bool, Reason)) -> Result<(), Error> {
+        if self.duplicate(&mail, decision.0) {
+            self.deduplicate(mail)?;
+            return Ok(());
+        }
+        self.act(mail, decision)
+    }
+
+    /// Is the message a duplicate to be deleted?
+    fn duplicate(&mut self, mail: &MailInfo, archive: bool) -> bool {
+        self.dedup
+            .as_mut()
+            .is_some_and(|dedup| dedup.check(mail, archive))
+    }
+
+    /// Archives the message or leaves it be, as decided.
+    fn act(&mut self, mail: MailInfo, decision: (bool, Reason)) -> Result<(), Error> {
+        match decision {
+            (true, reason) if self.opts.confirm => {
+                info!("Archive {}", mail);
+                self.archive(mail, reason)?;
+            }
+            (true, reason) => {
+                info!("Archive {}", mail);
+                self.report
+                    .record(mail.decision(Action::WouldArchive, reason));
+            }
+            (false, reason) => self.report.record(mail.decision(Action::Kept, reason)),
+        }
+        Ok(())
+    }
+
+    /// Removes a duplicate message, into the trash if there's one.
+    ///
+    /// Returns the space freed (or that would be freed without --confirm). Messages only marked
+    /// by the T flag stay in the maildir and free nothing yet.
+    fn deduplicate(&mut self, mail: MailInfo) -> Result<u64, Error> {
+        info!("Duplicate {}", mail);
+        let size = if self.opts.trash_flag { 0 } else { mail.size };
+        if !self.opts.confirm {
+            self.report
+                .record(mail.decision(Action::WouldDeduplicate, Reason::Duplicate));
+            return Ok(size);
+        }
+        let mut freed = 0;
+        let report = &mut self.report;
+        self.duplicates.put(mail, &mut |mail, removed| {
+            let action = if removed {
+                freed = size;
+                Action::Deduplicated
+            } else {
+                Action::Failed
+            };
+            report.record(mail.decision(action, Reason::Duplicate));
+        })?;
+        Ok(freed)
+    }
+
+    /// Deletes a message marked by the T flag if its grace period is over.
+    ///
+    /// Returns the space freed (or that would be freed without --confirm).
+    fn purge(&mut self, mail: MailInfo) -> Result<u64, Error> {
+        let now = self.criteria.now;
+        let times = self.times.as_mut().expect("Purging with the trash times");
+        let (action, reason) =
+            if times.trashed_at(mail.id.as_bytes(), now) >= self.opts.grace.before(now) {
+                (Action::Kept, Reason::InGrace)
+            } else {
+                info!("Purge {}", mail);
+                if !self.opts.confirm {
+                    (Action::WouldPurge, Reason::Trashed)
+                } else if let Err(e) = mail.delete() {
+                    error!("{:?}", e);
+                    (Action::Failed, Reason::Trashed)
+                } else {
+                    times.remove(mail.id.as_bytes());
+                    (Action::Purged, Reason::Trashed)
+                }
+            };
+        let size = if action.frees() { mail.size } else { 0 };
+        self.report.record(mail.decision(action, reason));
+        Ok(size)
+    }
+
+    /// Handles a message that failed to parse, quarantining it if asked to.
+    ///
+    /// Returns the space freed (or that would be freed without --confirm).
+    fn unparseable(&mut self, mail: &MailEntry, folder: &str, e: Error) -> Result<u64, Error> {
+        error!("{:?}", e);
+        // Taken before the file is moved away
+        let size = fs::metadata(mail.path()).map_or(0, |meta| meta.len());
+        let mut decision = Action::Kept;
+        let mut action = "kept";
+        if let Some(target) = &self.opts.quarantine {
+            let old = match mtime(mail.path()) {
+                Ok(time) => self.criteria.old(time),
+                Err(e) => {
+                    error!("{:?}", e);
+                    false
+                }
+            };
+            if old {
+                info!("Quarantine {}", mail.id());
+                decision = Action::WouldQuarantine;
+                action = "to quarantine";
+                if self.opts.confirm {
+                    match deliver::move_to(mail.path(), target, mail.id(), mail.flags()) {
+                        Ok(()) => {
+                            decision = Action::Quarantined;
+                            action = "quarantined";
+                        }
+                        Err(e) => {
+                            error!("{:?}", e.context("Failed to quarantine"));
+                            decision = Action::Failed;
+                            action = "quarantine failed";
+                        }
+                    }
+                }
+            }
+        }
+        self.report.record(Decision {
+            folder: folder.to_owned(),
+            path: mail.path().to_owned(),
+            size,
+            action: decision,
+            reason: Reason::Unparseable,
+        });
+        if let Some(report) = &mut self.parse_report {
+            let error = format!("{:#}", e).replace('\n', " ");
+            writeln!(report, "{}\t{}\t{}", mail.path().display(), action, error)
+                .context("Failed to write the parse report")?;
+        }
+        Ok(if decision.frees() { size } else { 0 })
+    }
+
+    /// Writes out everything pending and closes the archives.
+    ///
+    /// Failing to finish some of the archives is recorded in the [`Report::error`].
+    pub fn finish(mut self) -> Report {
+        if let Some(mut report) = self.parse_report.take() {
+            if let Err(e) = report.flush() {
+                let e = Error::from(e).context("Failed to write the parse report");
+                error!("{:?}", e);
+                self.report.error = Some(e);
+            }
+        }
+        let (report, reasons, dedup) = (&mut self.report, &mut self.reasons, &mut self.dedup);
+        let finished = self
+            .sink
+            .finish(&mut |mail, archived| sunk(report, reasons, dedup, mail, archived));
+        if let Err(e) = finished {
+            error!("{:?}", e);
+            self.report.error = Some(e);
+        }
+        if let Some(Err(e)) = self.dedup.as_mut().map(Dedup::save) {
+            error!("{:?}", e);
+            self.report.error = Some(e);
+        }
+        self.report
+    }
+}
+
+/// The result of a [`run`].
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Statistics of each processed folder.
+    pub folders: BTreeMap<String, Counts>,
+    /// Messages purged from the trash maildir (not belonging to any folder).
+    pub purged: usize,
+    /// Set if some of the archives failed to be completed.
+    ///
+    /// Some of the archived messages might be missing from the archives then.
+    pub error: Option<Error>,
+    /// What was done with each message, collected only with the json [`Options::report`].
+    pub decisions: Option<Vec<Decision>>,
+}
+
+impl Report {
+    /// Records the decision about a message.
+    fn record(&mut self, decision: Decision) {
+        match self.folders.get_mut(&decision.folder) {
+            Some(counts) => counts.record(&decision),
+            None => {
+                let mut counts = Counts::default();
+                counts.record(&decision);
+                self.folders.insert(decision.folder.clone(), counts);
+            }
+        }
+        if let Some(decisions) = &mut self.decisions {
+            decisions.push(decision);
+        }
+    }
+
+    /// How the run went as a whole.
+    pub fn status(&self) -> Status {
+        let total = self.total();
+        if self.error.is_some() {
+            Status::Fatal
+        } else if total.move_err > 0 {
+            Status::Partial
+        } else if total.archived
+            + total.quarantined
+            + total.purged
+            + total.duplicates
+            + total.pending
+            == 0
+        {
+            Status::NothingToDo
+        } else {
+            Status::Success
+        }
+    }
+
+    /// The statistics of all the folders together.
+    pub fn total(&self) -> Counts {
+        let mut total = Counts {
+            purged: self.purged,
+            ..Counts::default()
+        };
+        for counts in self.folders.values() {
+            total.add(counts);
+        }
+        total
+    }
+}
+
+/// Runs over all the maildirs in the options.
+///
+/// The options are expected to be [checked](Options::check). Errors that stop the run early are
+/// returned, failures to finish the archives are in the [`Report::error`].
+pub fn run(opts: &Options) -> Result<Report, Error> {
+    let mut run = Run::new(opts)?;
+
+    let extra = [&opts.archive_maildir, &opts.quarantine, &opts.trash];
+    for target in extra.iter().copied().flatten() {
+        if opts.confirm {
+            deliver::prepare(target)?;
+        }
+    }
+    if let Some(report) = &opts.parse_report {
+        // Appending, all the jobs from a config file share the report
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(report)
+            .with_context(|| format!("Failed to open {}", report.display()))?;
+        run.parse_report = Some(BufWriter::new(file));
+    }
+
+    // Don't archive the archive (or the quarantine or trash) if it happens to live inside the
+    // processed maildir
+    let skip = extra
+        .iter()
+        .copied()
+        .flatten()
+        .filter_map(|target| target.canonicalize().ok())
+        .collect::<Vec<_>>();
+    let mut mailboxes = Vec::new();
+    for maildir in &opts.maildir {
+        let folders = folders::discover(maildir, opts.recursive)?
+            .into_iter()
+            .filter(|folder| {
+                let path = folder.path.canonicalize().ok();
+                !path.is_some_and(|path| skip.contains(&path))
+            })
+            .collect::<Vec<_>>();
+        mailboxes.push(folders);
+    }
+    folders::check_names(&mailboxes.concat())?;
+    for folders in &mailboxes {
+        run.process_mailbox(folders)?;
+    }
+
+    let mut report = run.finish();
+    if let Some(trash) = &opts.trash {
+        let now = now();
+        report.purged = Trash::new(trash).purge(opts.grace.before(now), now, opts.confirm)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::date::Source;
+    use crate::testdir::{self, TempDir};
+
+    /// A message stored under the given file name in `cur`.
+    fn entry(maildir: &Path, name: &str, raw: &str) -> MailEntry {
+        fs::write(maildir.join("cur").join(format!("{}:2,S", name)), raw).unwrap();
+        Maildir::from(maildir.to_owned()).find(name).unwrap()
+    }
+
+    #[test]
+    fn date_sources() {
+        let dir = TempDir::new("main-dates");
+        let maildir = dir.maildir("box", &[]);
+        let dated = "Received: from a by b; Tue, 2 Jan 2024 10:00:00 +0000\n\
+                     Date: Mon, 1 Jan 2024 10:00:00 +0000\n\nBody\n";
+        let mut mail = entry(&maildir, "1700000000.M1.host", dated);
+        let all = [Source::Header, Source::Received, Source::Filename];
+        assert_eq!(1_704_103_200, resolve_date(&mut mail, &all).unwrap());
+        let mut mail = entry(&maildir, "1700000001.M1.host", dated);
+        assert_eq!(1_704_189_600, resolve_date(&mut mail, &all[1..]).unwrap());
+        let mut mail = entry(&maildir, "1700000002.M1.host", dated);
+        assert_eq!(1_700_000_002, resolve_date(&mut mail, &all[2..]).unwrap());
+
+        // Missing or broken headers fall through to the next source
+        let broken = "Date: someday\n\nBody\n";
+        let mut mail = entry(&maildir, "1700000003.M1.host", broken);
+        assert_eq!(1_700_000_003, resolve_date(&mut mail, &all).unwrap());
+        let mut mail = entry(&maildir, "1700000004.M1.host", broken);
+        let mtime = mtime(mail.path()).unwrap();
+        let sources = [Source::Header, Source::Received, Source::Mtime];
+        assert_eq!(mtime, resolve_date(&mut mail, &sources).unwrap());
+
+        // The error is of the last source tried
+        let mut mail = entry(&maildir, "nodate", broken);
+        let e = resolve_date(&mut mail, &all).unwrap_err();
+        assert_eq!("No delivery time in the file name", e.to_string());
+        let e = resolve_date(&mut mail, &all[..1]).unwrap_err();
+        assert_eq!("Broken Date header", e.to_string());
+    }
+
+    #[test]
+    fn trashed() {
+        let dir = TempDir::new("main-trash");
+        let maildir = dir.maildir("box", &["Date: Mon, 1 Jan 2024 10:00:00 +0000\n\nBody\n"]);
+        let trash = dir.path().join("trash");
+        let args = [
+            "decay",
+            "--dir",
+            maildir.to_str().unwrap(),
+            "--remove",
+            "--age",
+            "0",
+            "-c",
+        ];
+        let opts = Options::from_iter(args.iter().chain(&["--trash", trash.to_str().unwrap()]));
+        opts.check().unwrap();
+        run(&opts).unwrap();
+        assert!(testdir::mails(&maildir).is_empty());
+        assert!(trash.join("cur/0.test:2,S").exists());
+        assert_eq!(1, Trash::new(&trash).undo(None, true).unwrap());
+        assert_eq!(1, testdir::mails(&maildir).len());
+
+        // Marked only, and kept by the next run while in the grace period
+        let opts = Options::from_iter(args.iter().chain(&["--trash-flag"]));
+        opts.check().unwrap();
+        run(&opts).unwrap();
+        assert!(maildir.join("cur/0.test:2,ST").exists());
+        run(&opts).unwrap();
+        assert!(maildir.join("cur/0.test:2,ST").exists());
+        let log = fs::read_to_string(maildir.join("decay-trashed.log")).unwrap();
+        assert!(log.ends_with("\t0.test\n"));
+    }
+
+    #[test]
+    fn quarantined() {
+        let dir = TempDir::new("main-quarantine");
+        let good = "Date: Mon, 1 Jan 2024 10:00:00 +0000\n\nGood\n";
+        let maildir = dir.maildir("box", &[good, "Subject: No date\n\nBroken\n"]);
+        let broken = maildir.join("cur/1.test:2,S");
+        let (quarantine, parse_report) = (dir.path().join("quarantine"), dir.path().join("log"));
+        let archive = dir.path().join("archive");
+        let args = [
+            "decay",
+            "--dir",
+            maildir.to_str().unwrap(),
+            "--archive-maildir",
+            archive.to_str().unwrap(),
+            "--quarantine",
+            quarantine.to_str().unwrap(),
+            "--parse-report",
+            parse_report.to_str().unwrap(),
+            "--age",
+            "0",
+            "--report",
+            "json",
+        ];
+        let opts = Options::from_iter(&args);
+        opts.check().unwrap();
+
+        // A dry run leaves it where it is
+        let report = run(&opts).unwrap();
+        let total = report.total();
+        assert_eq!((1, 2), (total.parse_err, total.pending));
+        let decisions = report.decisions.unwrap();
+        let decision = decisions.iter().find(|d| d.path == broken).unwrap();
+        assert_eq!(
+            (Action::WouldQuarantine, Reason::Unparseable),
+            (decision.action, decision.reason)
+        );
+        assert!(broken.exists());
+        let log = fs::read_to_string(&parse_report).unwrap();
+        assert!(log.starts_with(&format!("{}\tto quarantine\t", broken.display())));
+
+        let opts = Options::from_iter(args.iter().chain(&["--confirm"]));
+        let report = run(&opts).unwrap();
+        let total = report.total();
+        assert_eq!(
+            (1, 1, 1),
+            (total.parse_err, total.quarantined, total.archived)
+        );
+        assert!(!broken.exists());
+        assert!(quarantine.join("cur/1.test:2,S").exists());
+        assert!(testdir::mails(&maildir).is_empty());
+        // Appended to the report of the first run
+        let log = fs::read_to_string(&parse_report).unwrap();
+        let line = log.lines().nth(1).unwrap();
+        assert!(line.starts_with(&format!("{}\tquarantined\t", broken.display())));
+        assert!(line.contains("Broken Date header"));
+    }
+
+    #[test]
+    fn unparseable_kept() {
+        let dir = TempDir::new("main-unparseable");
+        let maildir = dir.maildir("box", &["Subject: No date\n\nBroken\n"]);
+        let opts = Options::from_iter(&[
+            "decay",
+            "--dir",
+            maildir.to_str().unwrap(),
+            "--remove",
+            "--confirm",
+            "--report",
+            "json",
+        ]);
+        let mut run = Run::new(&opts).unwrap();
+        run.process(&folders::discover(&maildir, false).unwrap()[0])
+            .unwrap();
+        let report = run.finish();
+        assert!(report.error.is_none());
+        // Counted only as a parse error
+        let counts = &report.folders["box"];
+        assert_eq!((1, 0, 0), (counts.parse_err, counts.kept, counts.archived));
+        let decisions = report.decisions.unwrap();
+        assert_eq!(Action::Kept, decisions[0].action);
+        assert_eq!(Reason::Unparseable, decisions[0].reason);
+        assert!(maildir.join("cur/0.test:2,S").exists());
+    }
+}
@@ -1,245 +1,373 @@
-use std::ffi::OsStr;
-use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Write};
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
 use std::path::PathBuf;
-use std::process::Command;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::process;
 
-use anyhow::{ensure, Context, Error};
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use anyhow::{bail, Context, Error};
+use decay::report::{self, Status};
+use decay::trash::Trash;
+use decay::{compact, now, restore, run, Options, Report};
 use log::{error, info, warn, LevelFilter};
-use maildir::{MailEntry, Maildir};
-use mailparse::MailHeaderMap;
+use structopt::clap::{AppSettings, ArgMatches};
 use structopt::StructOpt;
 
+mod config;
+
+use config::{Config, ACTIONS};
+
 /// Tool to archive too old emails.
 ///
-/// Either deletes them or puts them to a maildbox file (optionally gzipped one).
+/// Either deletes them, puts them to a maildbox file (optionally compressed one) or moves them to
+/// another maildir.
+///
+/// Exits with 0 on success, 1 on a fatal error, 2 if some of the messages failed to be archived and
+/// 3 if there was nothing to do.
 #[derive(Debug, StructOpt)]
+#[structopt(global_settings = &[AppSettings::AllArgsOverrideSelf])]
 struct Opts {
-    /// The maildir to process and search for old messages.
-    #[structopt(short = "d", long = "dir", parse(from_os_str))]
-    maildir: PathBuf,
+    #[structopt(flatten)]
+    options: Options,
 
-    /// Where to put the old messages.
-    #[structopt(short = "a", long = "archive", parse(from_os_str))]
-    archive: Option<PathBuf>,
+    #[allow(dead_code)]
+    #[structopt(flatten)]
+    negations: Negations,
 
-    /// Remove messages instead of archiving.
-    #[structopt(short = "r", long = "remove")]
-    remove: bool,
+    /// Run the jobs from this configuration file.
+    ///
+    /// Each job is run as if its options were given on the command line. Options given on the
+    /// command line replace these from the file, for all the jobs. An action (--archive,
+    /// --archive-maildir, --archive-eml or --remove) replaces the action of the job and the
+    /// --no-* options turn off switches set in the file.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
 
-    /// Don't do a dry run only, actually run the actions.
-    #[structopt(short = "c", long = "confirm")]
-    confirm: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
 
-    /// Process "new" old emails too.
-    #[structopt(short = "n", long = "new")]
-    new: bool,
+/// Turning off the switches set in the --config file.
+///
+/// The fields are not read, the jobs look for the options in the matches by their names.
+#[allow(dead_code)]
+#[derive(Debug, StructOpt)]
+struct Negations {
+    /// Turn off --single-stream set in the --config file.
+    #[structopt(long = "no-single-stream", overrides_with = "single-stream")]
+    no_single_stream: bool,
 
-    /// Age in days.
-    #[structopt(short = "A", long = "age", default_value = "30")]
-    age: usize,
-}
+    /// Turn off --journal set in the --config file.
+    #[structopt(long = "no-journal", overrides_with = "journal")]
+    no_journal: bool,
 
-impl Opts {
-    fn check(&self) -> Result<(), Error> {
-        ensure!(
-            self.maildir.is_dir(),
-            "Maildir {} does not exist",
-            self.maildir.display()
-        );
-        ensure!(
-            self.archive.is_some() ^ self.remove,
-            "You can either archive or remove, not both"
-        );
+    /// Turn off --verify set in the --config file.
+    #[structopt(long = "no-verify", overrides_with = "verify")]
+    no_verify: bool,
 
-        Ok(())
-    }
-
-    fn destination(&self) -> Result<Box<dyn Write + Send + Sync>, Error> {
-        if self.remove {
-            Ok(Box::new(io::sink()))
-        } else {
-            let filename = self
-                .archive
-                .as_ref()
-                .expect("Already checked we have the file set");
-            let out = OpenOptions::new()
-                .read(false)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .append(true)
-                .open(filename)
-                .with_context(|| format!("Failed to write {}", filename.display()))?;
-            let out = BufWriter::new(out);
-
-            if filename.extension() == Some(OsStr::new("gz")) {
-                let out = GzEncoder::new(out, Compression::best());
-                Ok(Box::new(out))
-            } else {
-                Ok(Box::new(out))
-            }
-        }
-    }
-}
+    /// Turn off --trash-flag set in the --config file.
+    #[structopt(long = "no-trash-flag", overrides_with = "trash-flag")]
+    no_trash_flag: bool,
 
-struct Criteria {
-    before: i64,
-    must_seen: bool,
+    /// Turn off --recursive set in the --config file.
+    #[structopt(long = "no-recursive", overrides_with = "recursive")]
+    no_recursive: bool,
+
+    /// Turn off --threads set in the --config file.
+    #[structopt(long = "no-threads", overrides_with = "threads")]
+    no_threads: bool,
+
+    /// Turn off --keep-flagged-threads set in the --config file.
+    #[structopt(
+        long = "no-keep-flagged-threads",
+        overrides_with = "keep-flagged-threads"
+    )]
+    no_keep_flagged_threads: bool,
+
+    /// Turn off --new set in the --config file.
+    #[structopt(long = "no-new", overrides_with = "new")]
+    no_new: bool,
 }
 
-impl Criteria {
-    fn new(age: usize, must_seen: bool) -> Self {
-        let now = SystemTime::now();
-        let before = now - Duration::from_secs(3600 * 24 * (age as u64));
-        let before = before
-            .duration_since(UNIX_EPOCH)
-            .expect("Time before epoch");
-        Self {
-            before: before.as_secs() as i64,
-            must_seen,
-        }
-    }
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Rewrite a compressed archive into a single compression stream.
+    ///
+    /// Uses the --compression and --compression-level options.
+    Compact {
+        /// The archive to compact.
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+    },
+    /// Check the jobs in the --config file without running them.
+    CheckConfig,
+    /// Put messages from an archive back into a maildir.
+    ///
+    /// Only lists the messages unless --confirm is given. Messages can be chosen by their
+    /// Message-ID, by the --before and --after options and by a --select expression, all of which
+    /// must match. The archive is left untouched.
+    Restore {
+        /// The archive to restore from.
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
 
-    fn should_archive(&self, mail: &MailInfo) -> bool {
-        let old = mail.date_resolved <= self.before;
-        old && (!self.must_seen || mail.seen) && !mail.flagged
-    }
+        /// The maildir to put the messages into.
+        #[structopt(long = "to", parse(from_os_str))]
+        target: PathBuf,
+
+        /// Restore the message with this Message-ID (can be used multiple times).
+        #[structopt(long = "message-id", number_of_values = 1)]
+        message_id: Vec<String>,
+    },
+    /// Put messages from the --trash maildir back where they were removed from.
+    ///
+    /// Only the ones trashed at or after --after, if set. Only lists them unless --confirm is
+    /// given.
+    Undo,
 }
 
-struct MailInfo {
-    subject: String,
-    date: String,
-    date_resolved: i64,
-    id: String,
-    path: PathBuf,
-    seen: bool,
-    flagged: bool,
+/// Builds the options of each job in the config file.
+///
+/// The options given on the command line (the `cli` arguments, parsed into `matches`) replace the
+/// ones from the file.
+fn jobs(
+    config: &Config,
+    cli: &[OsString],
+    matches: &ArgMatches,
+) -> Result<Vec<(String, Options)>, Error> {
+    let given = |key: &str| matches.occurrences_of(key) > 0;
+    let action = ACTIONS.iter().any(|key| given(key));
+    let overridden = |key: &str| {
+        given(key) || given(&format!("no-{}", key)) || (action && ACTIONS.contains(&key))
+    };
+    let mut jobs = Vec::new();
+    for job in &config.jobs {
+        let mut args = vec!["decay".into()];
+        args.extend(config.args(job, overridden)?);
+        args.extend(cli.iter().cloned());
+        let opts = Opts::from_iter_safe(args)
+            .with_context(|| format!("Invalid options in job {}", job.name))?;
+        opts.options
+            .check()
+            .with_context(|| format!("Invalid job {}", job.name))?;
+        jobs.push((job.name.clone(), opts.options));
+    }
+    Ok(jobs)
 }
 
-impl MailInfo {
-    fn new(mail: &mut MailEntry) -> Result<Self, Error> {
-        let seen = mail.is_seen();
-        let flagged = mail.is_flagged();
-        let date_resolved = mail.date().context("Broken Date header")?;
-        let headers = mail.parsed().context("Can't parse mail")?;
-        let headers = headers.get_headers();
-        let date = headers.get_first_value("Date").unwrap_or_default();
-        let subject = headers.get_first_value("Subject").unwrap_or_default();
-        Ok(Self {
-            subject,
-            date,
-            date_resolved,
-            id: mail.id().to_owned(),
-            path: mail.path().to_owned(),
-            seen,
-            flagged,
-        })
-    }
-
-    fn archive(&self, dest: &mut dyn Write) -> Result<(), Error> {
-        let infile = File::open(&self.path)
-            .with_context(|| format!("Failed to open {}", self.path.display()))?;
-        let filtered = Command::new("formail")
-            .args(&["-I", "Status: RO"])
-            .stdin(infile)
-            .output()
-            .with_context(|| format!("Couldn't formail {}", self.path.display()))?;
-        ensure!(
-            filtered.status.success(),
-            "Formail on {} failed: {}",
-            self.path.display(),
-            filtered.status.success()
-        );
+/// Starts the parse reports afresh, the jobs then append to them.
+fn truncate_reports<'a>(jobs: impl IntoIterator<Item = &'a Options>) -> Result<(), Error> {
+    for report in jobs
+        .into_iter()
+        .filter_map(|opts| opts.parse_report.as_ref())
+    {
+        File::create(report).with_context(|| format!("Failed to create {}", report.display()))?;
+    }
+    Ok(())
+}
 
-        dest.write_all(&filtered.stdout)
-            .context("Failed to output email")?;
+/// Logs the statistics of a run.
+fn summary(opts: &Options, report: &Report) {
+    if opts.recursive || opts.maildir.len() > 1 {
+        for (folder, counts) in &report.folders {
+            info!(
+                "{}: archived {}, kept {}, parse errors {}, move errors {}",
+                folder, counts.archived, counts.kept, counts.parse_err, counts.move_err
+            );
+        }
+    }
 
-        Ok(())
+    let total = report.total();
+    info!("Archived: {}", total.archived);
+    info!("Kept: {}", total.kept);
+    if total.parse_err > 0 {
+        warn!("Parse errors: {}", total.parse_err);
+    }
+    if total.quarantined > 0 {
+        info!("Quarantined: {}", total.quarantined);
+    }
+    if total.purged > 0 {
+        info!("Purged: {}", total.purged);
+    }
+    if total.duplicates > 0 {
+        info!("Duplicates: {}", total.duplicates);
+    }
+    if total.pending > 0 {
+        info!("Pending (dry run): {}", total.pending);
+    }
+    if total.freed > 0 {
+        let freed = if opts.confirm { "Freed" } else { "Would free" };
+        info!("{}: {} bytes", freed, total.freed);
+    }
+    if total.move_err > 0 {
+        warn!("Move errors: {}", total.move_err);
     }
 }
 
-impl Display for MailInfo {
-    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        write!(fmt, "{}/{}/{}", self.id, self.date, self.subject)
+/// Runs one set of options (one job), logs the summary and prints the report.
+fn run_job(name: Option<&str>, opts: &Options) -> Status {
+    let report = run(opts);
+    if opts.report == report::Format::Json {
+        println!("{}", report::json(name, report.as_ref()));
+    }
+    match report {
+        Ok(report) => {
+            // The error of finishing the archives is already logged by the run
+            summary(opts, &report);
+            report.status()
+        }
+        Err(e) => {
+            match name {
+                Some(name) => error!("Job {} failed: {:?}", name, e),
+                None => error!("{:?}", e),
+            }
+            Status::Fatal
+        }
     }
 }
 
-fn main() -> Result<(), Error> {
+fn main() {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
         .parse_default_env()
         .init();
 
-    let opts = Opts::from_args();
-    opts.check()?;
-
-    let mut dest = opts
-        .destination()
-        .context("Failed to open the destination")?;
-
-    let dir = Maildir::from(opts.maildir);
-    let mails = dir.list_cur();
-    let mails = if opts.new {
-        Box::new(mails.chain(dir.list_new())) as Box<dyn Iterator<Item = _>>
-    } else {
-        Box::new(mails)
+    let matches = Opts::clap().get_matches();
+    let status = match start(Opts::from_clap(&matches), &matches) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Status::Fatal
+        }
     };
+    process::exit(status.code());
+}
 
-    let criteria = Criteria::new(opts.age, !opts.new);
-    let mut archived = 0usize;
-    let mut kept = 0usize;
-    let mut parse_err = 0usize;
-    let mut move_err = 0usize;
-
-    for mail in mails {
-        let mail = mail.map_err(Error::from).and_then(|mut m| {
-            MailInfo::new(&mut m).with_context(|| format!("Failed to parse email {}", m.id()))
-        });
-
-        match mail {
-            Ok(mail) => {
-                if criteria.should_archive(&mail) {
-                    info!("Archive {}", mail);
-                    if opts.confirm {
-                        let deleted = mail
-                            .archive(&mut dest)
-                            .with_context(|| format!("Failed to move mail {}", mail))
-                            .and_then(|()| {
-                                dir.delete(&mail.id)
-                                    .with_context(|| format!("Failed to delete mail {}", mail))
-                            });
-                        match deleted {
-                            Ok(()) => archived += 1,
-                            Err(e) => {
-                                error!("{:?}", e);
-                                move_err += 1;
-                            }
-                        }
-                    }
-                } else {
-                    kept += 1;
-                }
-            }
-            Err(e) => {
-                error!("{:?}", e);
-                parse_err += 1;
-            }
+/// Does what the command line asks for.
+///
+/// Errors before any run starts are returned, the runs themselves end with a status.
+fn start(cli: Opts, matches: &ArgMatches) -> Result<Status, Error> {
+    let opts = &cli.options;
+    match &cli.command {
+        Some(Command::Compact { archive }) => {
+            compact(opts, archive)?;
+            return Ok(Status::Success);
+        }
+        Some(Command::Undo) => {
+            let trash = opts.trash.as_ref().context("No --trash to undo")?;
+            let undone = Trash::new(trash).undo(opts.after, opts.confirm)?;
+            info!("Put back: {}", undone);
+            return Ok(Status::Success);
         }
+        Some(Command::Restore {
+            archive,
+            target,
+            message_id,
+        }) => {
+            let filter = restore::Filter {
+                message_ids: message_id,
+                before: opts.before,
+                after: opts.after,
+                select: opts.select.as_ref(),
+            };
+            let restored = restore::restore(
+                archive,
+                opts.compression(archive),
+                opts.mbox_format,
+                target,
+                &filter,
+                now(),
+                opts.confirm,
+            )?;
+            info!("Restored: {}", restored);
+            return Ok(Status::Success);
+        }
+        _ => (),
     }
+    let config = match (&cli.config, &cli.command) {
+        (Some(config), _) => config,
+        (None, Some(Command::CheckConfig)) => bail!("No --config to check"),
+        (None, _) => {
+            opts.check()?;
+            truncate_reports(Some(opts))?;
+            return Ok(run_job(None, opts));
+        }
+    };
 
-    info!("Archived: {}", archived);
-    info!("Kept: {}", kept);
-    if parse_err > 0 {
-        warn!("Parse errors: {}", parse_err);
+    // Check all the jobs before running any of them
+    let args = env::args_os().skip(1).collect::<Vec<_>>();
+    let jobs = jobs(&Config::load(config)?, &args, matches)?;
+    if let Some(Command::CheckConfig) = cli.command {
+        info!("Configuration OK, {} jobs", jobs.len());
+        return Ok(Status::Success);
     }
-    if move_err > 0 {
-        warn!("Move errors: {}", move_err);
+    truncate_reports(jobs.iter().map(|(_, opts)| opts))?;
+    // The worst of the jobs, the others still run after a failure
+    let mut status = Status::NothingToDo;
+    for (name, opts) in &jobs {
+        info!("Job {}", name);
+        status = status.max(run_job(Some(name), opts));
     }
+    Ok(status)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use decay::date::{Age, DAY};
+
+    use super::*;
+
+    /// Existing directories as the maildirs, the jobs are only built, not run.
+    fn dirs() -> [PathBuf; 3] {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        [env::temp_dir(), root.join("src"), root]
+    }
+
+    fn jobs_with(cli: &[&str]) -> Vec<(String, Options)> {
+        let [a, b, _] = dirs();
+        let config = format!(
+            "recursive = true\n\
+             [job.a]\nmaildir = \"{}\"\nage = 14\nremove = true\n\
+             [job.b]\nmaildir = \"{}\"\nverify = true\narchive = \"{}\"\n",
+            a.display(),
+            b.display(),
+            a.join("b.mbox").display()
+        );
+        let cli = cli.iter().map(OsString::from).collect::<Vec<_>>();
+        let matches = Opts::clap()
+            .get_matches_from_safe(Some(OsString::from("decay")).into_iter().chain(cli.clone()))
+            .unwrap();
+        jobs(&Config::parse(&config).unwrap(), &cli, &matches).unwrap()
+    }
+
+    #[test]
+    fn from_config() {
+        let jobs = jobs_with(&[]);
+        let (a, b) = (&jobs[0].1, &jobs[1].1);
+        assert_eq!(vec![dirs()[0].clone()], a.maildir);
+        assert_eq!(Age::Seconds(14 * DAY), a.age);
+        assert_eq!(Age::Seconds(30 * DAY), b.age);
+        assert!(a.recursive && b.recursive);
+        assert!(a.remove && !b.remove);
+        assert!(b.verify);
+    }
+
+    #[test]
+    fn overridden() {
+        let [_, _, other] = dirs();
+        let dir = other.to_str().unwrap();
+        for (_, opts) in jobs_with(&["--age", "90", "--no-recursive", "--dir", dir]) {
+            assert_eq!(Age::Seconds(90 * DAY), opts.age);
+            assert!(!opts.recursive);
+            assert_eq!(vec![other.clone()], opts.maildir);
+        }
+
+        // The action replaces the action of each job, whatever it was
+        let archive = env::temp_dir().join("archive");
+        let archive = archive.to_str().unwrap();
+        for (_, opts) in jobs_with(&["--archive-maildir", archive, "--no-verify"]) {
+            assert!(!opts.remove && opts.archive.is_none() && !opts.verify);
+            assert_eq!(Some(PathBuf::from(archive)), opts.archive_maildir);
+            assert!(opts.recursive);
+        }
+    }
 }
@@ -0,0 +1,695 @@
+//! Serialization of messages into the mbox format.
+//!
+//! The default [`Format::Mboxo`] produces the same output `formail -I "Status: RO"` used to for
+//! read messages, so archives written by older versions and by this one can be freely mixed.
+//! Unread messages get `Status: O` instead (as mutt writes them) and the other maildir flags go
+//! into `X-Status`. The other dialects differ in how they keep `From ` lines in the body from
+//! being mistaken for message separators.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, Result as IoResult, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Error};
+use mailparse::{addrparse, parse_headers, MailAddr, MailHeaderMap};
+
+use crate::date::Civil;
+
+/// Sender put into the `From ` line if we can't find anything better in the message.
+const DEFAULT_SENDER: &str = "MAILER-DAEMON";
+
+/// The flavour of the mbox format.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Body lines starting with `From ` are quoted by `>`.
+    ///
+    /// This is not reversible, a line that started with `>From ` reads back as `From `.
+    Mboxo,
+    /// Body lines starting with any number of `>` followed by `From ` get one more `>`.
+    Mboxrd,
+    /// Like mboxo, but with a `Content-Length` header.
+    Mboxcl,
+    /// A `Content-Length` header determines the message boundaries, no quoting is done.
+    Mboxcl2,
+}
+
+impl Format {
+    /// Names of all the formats, as accepted on the command line.
+    pub const NAMES: &'static [&'static str] = &["mboxo", "mboxrd", "mboxcl", "mboxcl2"];
+
+    pub(crate) fn content_length(self) -> bool {
+        matches!(self, Format::Mboxcl | Format::Mboxcl2)
+    }
+
+    /// Does this body line need a `>` prepended?
+    fn needs_quote(self, line: &[u8]) -> bool {
+        match self {
+            Format::Mboxo | Format::Mboxcl => line.starts_with(b"From "),
+            Format::Mboxrd => is_quoted_from(line),
+            Format::Mboxcl2 => false,
+        }
+    }
+
+    /// Does this body line have a `>` to remove when reading?
+    fn is_quoted(self, line: &[u8]) -> bool {
+        match self {
+            Format::Mboxo | Format::Mboxcl => line.starts_with(b">From "),
+            Format::Mboxrd => line.starts_with(b">") && is_quoted_from(line),
+            Format::Mboxcl2 => false,
+        }
+    }
+
+    fn quote(self, body: &[u8]) -> Cow<'_, [u8]> {
+        if !lines(body).any(|line| self.needs_quote(line)) {
+            return Cow::Borrowed(body);
+        }
+        let mut quoted = Vec::with_capacity(body.len() + 16);
+        for line in lines(body) {
+            if self.needs_quote(line) {
+                quoted.push(b'>');
+            }
+            quoted.extend_from_slice(line);
+        }
+        Cow::Owned(quoted)
+    }
+
+    fn unquote(self, body: &[u8], out: &mut Vec<u8>) {
+        for line in lines(body) {
+            if self.is_quoted(line) {
+                out.extend_from_slice(&line[1..]);
+            } else {
+                out.extend_from_slice(line);
+            }
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "mboxo" => Ok(Format::Mboxo),
+            "mboxrd" => Ok(Format::Mboxrd),
+            "mboxcl" => Ok(Format::Mboxcl),
+            "mboxcl2" => Ok(Format::Mboxcl2),
+            _ => bail!("Unknown mbox format {}", s),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Format::Mboxo => "mboxo",
+            Format::Mboxrd => "mboxrd",
+            Format::Mboxcl => "mboxcl",
+            Format::Mboxcl2 => "mboxcl2",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Is this `From ` preceded by any number (including 0) of `>`?
+fn is_quoted_from(line: &[u8]) -> bool {
+    let unquoted = line.iter().position(|&b| b != b'>').unwrap_or(line.len());
+    line[unquoted..].starts_with(b"From ")
+}
+
+/// Splits a raw message into lines, keeping the line terminators.
+pub(crate) fn lines(raw: &[u8]) -> impl Iterator<Item = &[u8]> {
+    raw.split_inclusive(|&b| b == b'\n')
+}
+
+fn is_empty_line(line: &[u8]) -> bool {
+    line == b"\n" || line == b"\r\n"
+}
+
+/// Splits the message into the header and the body.
+///
+/// The empty line separating them belongs to neither of them.
+pub(crate) fn split(raw: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    for line in lines(raw) {
+        if is_empty_line(line) {
+            return (&raw[..pos], &raw[pos + line.len()..]);
+        }
+        pos += line.len();
+    }
+    (raw, &[])
+}
+
+/// Is this line a start of the given header field (case insensitive)?
+pub(crate) fn is_field(line: &[u8], name: &str) -> bool {
+    line.len() > name.len()
+        && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+        && line[name.len()] == b':'
+}
+
+/// Formats the time the way `ctime` does (without the trailing newline).
+fn ctime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Civil::from_timestamp(secs).ctime()
+}
+
+/// Finds the envelope sender of the message.
+///
+/// Prefers the `Return-Path` header, then the `From` address.
+fn sender(header: &[u8]) -> String {
+    let headers = match parse_headers(header) {
+        Ok((headers, _)) => headers,
+        Err(_) => return DEFAULT_SENDER.to_owned(),
+    };
+    let return_path = headers.get_first_value("Return-Path").and_then(|path| {
+        let path = path.trim().trim_start_matches('<').trim_end_matches('>');
+        Some(path.to_owned()).filter(|p| !p.is_empty())
+    });
+    let from = || {
+        let from = headers.get_first_value("From")?;
+        addrparse(&from).ok()?.iter().find_map(|addr| match addr {
+            MailAddr::Single(single) => Some(single.addr.clone()),
+            MailAddr::Group(group) => group.addrs.first().map(|a| a.addr.clone()),
+        })
+    };
+    return_path
+        .or_else(from)
+        .filter(|s| !s.contains(char::is_whitespace))
+        .unwrap_or_else(|| DEFAULT_SENDER.to_owned())
+}
+
+/// The `X-Status` letters (as used by mutt) of the maildir flags.
+const X_STATUS: &[(u8, u8)] = &[(b'R', b'A'), (b'F', b'F'), (b'D', b'T'), (b'T', b'D')];
+
+/// Writes one message in the mbox format.
+///
+/// The raw message is as stored in a maildir, with the maildir `flags`. The output starts with the
+/// `From ` separator line (generated from the sender and the `time`, unless the message already
+/// has one), any `Status` and `X-Status` headers are replaced by ones made from the flags and the
+/// body is quoted according to the `format`.
+///
+/// For the formats without `Content-Length` the body is terminated by a newline if it lacks one.
+/// Every message is followed by an empty line.
+pub fn write(
+    dest: &mut dyn Write,
+    raw: &[u8],
+    flags: &str,
+    time: SystemTime,
+    format: Format,
+) -> IoResult<()> {
+    let (header, body) = split(raw);
+    let mut header_lines = lines(header).peekable();
+
+    match header_lines.peek() {
+        Some(first) if first.starts_with(b"From ") => {
+            dest.write_all(first)?;
+            header_lines.next();
+        }
+        _ => writeln!(dest, "From {} {}", sender(header), ctime(time))?,
+    }
+
+    let mut skipping = false;
+    for line in header_lines {
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation {
+            skipping = is_field(line, "Status")
+                || is_field(line, "X-Status")
+                || (format.content_length() && is_field(line, "Content-Length"));
+        }
+        if !skipping {
+            dest.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                dest.write_all(b"\n")?;
+            }
+        }
+    }
+    let seen = if flags.contains('S') { "RO" } else { "O" };
+    writeln!(dest, "Status: {}", seen)?;
+    let x_status = X_STATUS
+        .iter()
+        .filter(|(flag, _)| flags.as_bytes().contains(flag))
+        .map(|&(_, letter)| letter)
+        .collect::<Vec<_>>();
+    if !x_status.is_empty() {
+        dest.write_all(b"X-Status: ")?;
+        dest.write_all(&x_status)?;
+        dest.write_all(b"\n")?;
+    }
+
+    let body = format.quote(body);
+    let terminate = !format.content_length() && !body.is_empty() && !body.ends_with(b"\n");
+    if format.content_length() {
+        writeln!(dest, "Content-Length: {}", body.len())?;
+    }
+    dest.write_all(b"\n")?;
+    dest.write_all(&body)?;
+    if terminate {
+        dest.write_all(b"\n")?;
+    }
+    dest.write_all(b"\n")
+}
+
+/// Removes the `Status` and `X-Status` headers, turning them into maildir flags.
+///
+/// Returns the message without the headers and the flags (sorted, as maildir wants them).
+/// `Status: R` means seen, the `X-Status` (as used by mutt) letters `A` replied, `F` flagged, `T`
+/// draft and `D` trashed.
+pub fn take_status(raw: &[u8]) -> (Vec<u8>, String) {
+    let (header, _) = split(raw);
+    let mut stripped = Vec::with_capacity(raw.len());
+    let mut flags = Vec::new();
+    let mut skipping = false;
+    for line in lines(header) {
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation {
+            let status = is_field(line, "Status");
+            skipping = status || is_field(line, "X-Status");
+            if skipping {
+                let value = line.splitn(2, |&b| b == b':').nth(1).unwrap_or_default();
+                flags.extend(value.iter().filter_map(|c| {
+                    match (status, c) {
+                        (true, b'R') => Some(b'S'),
+                        (true, _) => None,
+                        (false, c) => X_STATUS
+                            .iter()
+                            .find(|&&(_, letter)| letter == *c)
+                            .map(|&(flag, _)| flag),
+                    }
+                }));
+            }
+        }
+        if !skipping {
+            stripped.extend_from_slice(line);
+        }
+    }
+    stripped.extend_from_slice(&raw[header.len()..]);
+    flags.sort_unstable();
+    flags.dedup();
+    (stripped, String::from_utf8(flags).expect("Flags are ASCII"))
+}
+
+/// Finds the body of a message using its `Content-Length` header.
+///
+/// The `rest` starts just after the `From ` line. Returns the header (without the
+/// `Content-Length`), the body and what follows the message, if the header is present and valid.
+fn by_content_length(rest: &[u8]) -> Option<(Vec<u8>, &[u8], &[u8])> {
+    let (header, body_and_rest) = split(rest);
+    let mut length = None;
+    let mut stripped = Vec::with_capacity(header.len());
+    let mut skipping = false;
+    for line in lines(header) {
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation {
+            skipping = is_field(line, "Content-Length");
+            if skipping {
+                let value = std::str::from_utf8(&line["Content-Length:".len()..]).ok()?;
+                length = Some(value.trim().parse::<usize>().ok()?);
+            }
+        }
+        if !skipping {
+            stripped.extend_from_slice(line);
+        }
+    }
+    let length = length?;
+    if body_and_rest.len() < length {
+        return None;
+    }
+    let (body, after) = body_and_rest.split_at(length);
+    let after = match after {
+        [] => after,
+        [b'\n', next @ ..] | [b'\r', b'\n', next @ ..]
+            if next.is_empty() || next.starts_with(b"From ") =>
+        {
+            next
+        }
+        _ => return None,
+    };
+    Some((stripped, body, after))
+}
+
+/// Finds the end of a message by looking for the next `From ` line after an empty one.
+///
+/// Returns the message and what follows it.
+fn by_separator(rest: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    // The length of the previous line, if it was an empty one
+    let mut empty = None;
+    for line in lines(rest) {
+        match empty {
+            Some(len) if line.starts_with(b"From ") => return (&rest[..pos - len], &rest[pos..]),
+            _ => (),
+        }
+        empty = Some(line.len()).filter(|_| is_empty_line(line));
+        pos += line.len();
+    }
+    match lines(rest).last() {
+        Some(last) if is_empty_line(last) => (&rest[..rest.len() - last.len()], &[]),
+        _ => (rest, &[]),
+    }
+}
+
+/// Parses an mbox into the individual messages.
+///
+/// The messages are returned without the `From ` lines, with the quoting undone and (for the
+/// `Content-Length` formats) without the `Content-Length` header. Anything before the first `From
+/// ` line is ignored.
+pub fn read(data: &[u8], format: Format) -> Vec<Vec<u8>> {
+    parse(data, format, false).unwrap_or_default()
+}
+
+/// Parses an mbox, or a piece of one, into the individual messages.
+///
+/// With `more` set, the mbox goes on after the `data`. `None` is returned if the `Content-Length`
+/// of the last message reaches past the `data` then.
+fn parse(data: &[u8], format: Format, more: bool) -> Option<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+    let mut rest = match lines(data).position(|line| line.starts_with(b"From ")) {
+        Some(idx) => &data[lines(data).take(idx).map(<[u8]>::len).sum::<usize>()..],
+        None => return Some(messages),
+    };
+
+    while !rest.is_empty() {
+        let from_len = lines(rest).next().map(<[u8]>::len).unwrap_or_default();
+        let message = &rest[from_len..];
+        let mut raw = Vec::with_capacity(message.len());
+
+        let parsed = if format.content_length() {
+            by_content_length(message)
+        } else {
+            None
+        };
+        if parsed.is_none() && more && format.content_length() && cut_short(rest) {
+            return None;
+        }
+        if let Some((header, body, after)) = parsed {
+            raw.extend_from_slice(&header);
+            raw.push(b'\n');
+            format.unquote(body, &mut raw);
+            rest = after;
+        } else {
+            let (message, after) = by_separator(message);
+            let (header, body) = split(message);
+            raw.extend_from_slice(header);
+            if header.len() < message.len() {
+                raw.extend_from_slice(&message[header.len()..message.len() - body.len()]);
+            }
+            format.unquote(body, &mut raw);
+            rest = after;
+        }
+        messages.push(raw);
+    }
+
+    Some(messages)
+}
+
+/// Is the body of the message starting this piece of mbox shorter than its `Content-Length`
+/// header says?
+fn cut_short(chunk: &[u8]) -> bool {
+    let from_len = lines(chunk).next().map(<[u8]>::len).unwrap_or_default();
+    let (header, body) = split(&chunk[from_len..]);
+    let length = lines(header)
+        .find(|line| is_field(line, "Content-Length"))
+        .and_then(|line| std::str::from_utf8(&line["Content-Length:".len()..]).ok())
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    length.is_some_and(|length| body.len() < length)
+}
+
+/// Reads an mbox message by message, without holding all of it in memory.
+///
+/// Yields the same messages as [`read`] does.
+pub struct Reader<R> {
+    input: R,
+    format: Format,
+    /// A separator line read ahead, starting the next message.
+    next_from: Option<Vec<u8>>,
+    /// Messages already split off.
+    ready: VecDeque<Vec<u8>>,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R, format: Format) -> Self {
+        Self {
+            input,
+            format,
+            next_from: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Reads up to the next message separator (a `From ` line after an empty one).
+    fn chunk(&mut self) -> IoResult<Option<Vec<u8>>> {
+        let mut chunk = self.next_from.take().unwrap_or_default();
+        let mut prev_empty = false;
+        loop {
+            let mut line = Vec::new();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                return Ok(Some(chunk).filter(|chunk| !chunk.is_empty()));
+            }
+            if prev_empty && line.starts_with(b"From ") {
+                self.next_from = Some(line);
+                return Ok(Some(chunk));
+            }
+            prev_empty = is_empty_line(&line);
+            chunk.extend_from_slice(&line);
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = IoResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<IoResult<Vec<u8>>> {
+        while self.ready.is_empty() {
+            let mut data = match self.chunk() {
+                Ok(Some(data)) => data,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            // A body with Content-Length may go on past what looked like a separator
+            let messages = loop {
+                let more = self.next_from.is_some();
+                if let Some(messages) = parse(&data, self.format, more) {
+                    break messages;
+                }
+                match self.chunk() {
+                    Ok(Some(next)) => data.extend_from_slice(&next),
+                    Ok(None) => break read(&data, self.format),
+                    Err(e) => return Some(Err(e)),
+                }
+            };
+            self.ready.extend(messages);
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const FORMATS: &[Format] = &[
+        Format::Mboxo,
+        Format::Mboxrd,
+        Format::Mboxcl,
+        Format::Mboxcl2,
+    ];
+
+    const PLAIN: &[u8] = b"From: Someone <someone@example.com>\n\
+Subject: Hello\n\
+Status: RO\n\
+\n\
+Hello\n\
+\n\
+From the other side\n";
+
+    const QUOTED: &[u8] = b"From: Someone <someone@example.com>\n\
+Status: RO\n\
+\n\
+>From here\n\
+>>From there\n\
+\n\
+From everywhere\n";
+
+    const UNTERMINATED: &[u8] = b"Subject: No newline\n\
+Status: RO\n\
+\n\
+From the start\n\
+\n\
+and no end";
+
+    fn roundtrip(format: Format, messages: &[&[u8]]) {
+        let mut mbox = Vec::new();
+        for msg in messages {
+            write(&mut mbox, msg, "S", UNIX_EPOCH, format).unwrap();
+        }
+        let read = read(&mbox, format);
+        assert_eq!(messages, read.as_slice(), "{}", format);
+        let streamed = Reader::new(&mbox[..], format)
+            .collect::<IoResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, streamed, "{}", format);
+    }
+
+    fn written(msg: &[u8]) -> Vec<u8> {
+        let mut mbox = Vec::new();
+        write(&mut mbox, msg, "S", UNIX_EPOCH, Format::Mboxo).unwrap();
+        mbox
+    }
+
+    #[test]
+    fn from_line() {
+        assert!(written(PLAIN).starts_with(b"From someone@example.com Thu Jan  1 00:00:00 1970\n"));
+        let msg = b"Return-Path: <bounce@example.com>\nFrom: a@example.com\n\nBody\n";
+        assert!(written(msg).starts_with(b"From bounce@example.com "));
+        assert!(written(b"Subject: x\n\nBody\n").starts_with(b"From MAILER-DAEMON "));
+        // Already there
+        let msg = b"From other@example.com Mon Jan  1 10:00:00 2024\nSubject: x\n\nBody\n";
+        assert!(
+            written(msg).starts_with(b"From other@example.com Mon Jan  1 10:00:00 2024\nSubject")
+        );
+    }
+
+    #[test]
+    fn ctime_format() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!("Thu Feb 29 12:34:56 2024", ctime(time));
+    }
+
+    #[test]
+    fn status_replaced() {
+        let msg = b"Status: O\n\tcontinued\nX-Status: F\nSubject: Hi\n\nBody\n";
+        assert_eq!(
+            &b"From MAILER-DAEMON Thu Jan  1 00:00:00 1970\nSubject: Hi\nStatus: RO\n\nBody\n\n"[..],
+            &written(msg)[..]
+        );
+    }
+
+    #[test]
+    fn body_escaped() {
+        let expected = b"From someone@example.com Thu Jan  1 00:00:00 1970\n\
+From: Someone <someone@example.com>\n\
+Subject: Hello\n\
+Status: RO\n\
+\n\
+Hello\n\
+\n\
+>From the other side\n\
+\n";
+        assert_eq!(&expected[..], &written(PLAIN)[..]);
+        // Terminated by a newline if missing
+        assert!(written(b"Subject: x\n\nno end").ends_with(b"\n\nno end\n\n"));
+    }
+
+    #[test]
+    fn flags_kept() {
+        for flags in &["", "S", "FS", "DFRST", "R"] {
+            let mut mbox = Vec::new();
+            write(&mut mbox, PLAIN, flags, UNIX_EPOCH, Format::Mboxrd).unwrap();
+            let read = read(&mbox, Format::Mboxrd);
+            let (stripped, taken) = take_status(&read[0]);
+            assert_eq!(*flags, taken);
+            assert!(!stripped.windows(7).any(|w| w == b"Status:"));
+        }
+        let mut mbox = Vec::new();
+        write(
+            &mut mbox,
+            b"Subject: x\n\ny\n",
+            "RF",
+            UNIX_EPOCH,
+            Format::Mboxo,
+        )
+        .unwrap();
+        assert!(mbox.ends_with(b"Subject: x\nStatus: O\nX-Status: AF\n\ny\n\n"));
+    }
+
+    #[test]
+    fn roundtrip_plain() {
+        for &format in FORMATS {
+            roundtrip(format, &[PLAIN]);
+            roundtrip(format, &[PLAIN, PLAIN, PLAIN]);
+        }
+    }
+
+    #[test]
+    fn roundtrip_quoted() {
+        roundtrip(Format::Mboxrd, &[QUOTED, PLAIN]);
+        roundtrip(Format::Mboxcl2, &[QUOTED, PLAIN]);
+    }
+
+    #[test]
+    fn mboxo_lossy() {
+        let mut mbox = Vec::new();
+        write(&mut mbox, QUOTED, "S", UNIX_EPOCH, Format::Mboxo).unwrap();
+        let read = read(&mbox, Format::Mboxo);
+        assert_ne!(QUOTED, read[0].as_slice());
+    }
+
+    #[test]
+    fn roundtrip_unterminated() {
+        roundtrip(Format::Mboxcl2, &[UNTERMINATED, PLAIN]);
+        roundtrip(Format::Mboxcl2, &[PLAIN, UNTERMINATED]);
+    }
+
+    #[test]
+    fn streamed() {
+        // Junk before the first message and a Content-Length spanning what looks like separators
+        let mut mbox = b"junk\n\n".to_vec();
+        for msg in &[PLAIN, UNTERMINATED, QUOTED, PLAIN] {
+            write(&mut mbox, msg, "S", UNIX_EPOCH, Format::Mboxcl2).unwrap();
+        }
+        for &format in FORMATS {
+            let streamed = Reader::new(&mbox[..], format)
+                .collect::<IoResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(read(&mbox, format), streamed, "{}", format);
+        }
+        assert_eq!(0, Reader::new(&b""[..], Format::Mboxo).count());
+    }
+
+    #[test]
+    fn crlf_separators() {
+        let mbox = b"From a Thu Jan  1 00:00:00 1970\r\nSubject: 1\r\n\r\nOne\r\n\r\n\
+From b Thu Jan  1 00:00:00 1970\r\nSubject: 2\r\n\r\nTwo\r\n\r\n";
+        let expected = vec![
+            b"Subject: 1\r\n\r\nOne\r\n".to_vec(),
+            b"Subject: 2\r\n\r\nTwo\r\n".to_vec(),
+        ];
+        for &format in FORMATS {
+            assert_eq!(expected, read(mbox, format), "{}", format);
+            let streamed = Reader::new(&mbox[..], format)
+                .collect::<IoResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(expected, streamed, "{}", format);
+        }
+    }
+
+    #[test]
+    fn status_taken() {
+        let raw = b"Subject: x\nStatus: RO\nX-Status: AF\nFrom: a@b\n\nStatus: R\n";
+        let (stripped, flags) = take_status(raw);
+        assert_eq!(&b"Subject: x\nFrom: a@b\n\nStatus: R\n"[..], &stripped[..]);
+        assert_eq!("FRS", flags);
+
+        let (stripped, flags) = take_status(b"Subject: x\n\nbody\n");
+        assert_eq!(&b"Subject: x\n\nbody\n"[..], &stripped[..]);
+        assert_eq!("", flags);
+    }
+
+    #[test]
+    fn content_length_replaced() {
+        let msg = b"Content-Length: 42\nStatus: RO\n\nBody\n";
+        let mut mbox = Vec::new();
+        write(&mut mbox, msg, "S", UNIX_EPOCH, Format::Mboxcl2).unwrap();
+        assert!(mbox.ends_with(b"\nStatus: RO\nContent-Length: 5\n\nBody\n\n"));
+        assert_eq!(
+            vec![b"Status: RO\n\nBody\n".to_vec()],
+            read(&mbox, Format::Mboxcl2)
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Keeping a number of the newest messages.
+//!
+//! The messages of a folder are split into groups (the whole folder, the sender or the mailing
+//! list) and the newest ones of each group are kept regardless of their age.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+use crate::select::Message;
+
+/// What the messages are counted by.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Group {
+    /// All the messages of the folder together.
+    Folder,
+    /// The address in the From header.
+    From,
+    /// The List-Id header.
+    ListId,
+}
+
+impl Group {
+    pub const NAMES: &'static [&'static str] = &["folder", "from", "list-id"];
+
+    /// The group the message belongs to.
+    ///
+    /// Messages without the header are in none.
+    pub fn key<M: Message>(self, mail: &M) -> Option<String> {
+        let header = match self {
+            Group::Folder => return Some(String::new()),
+            Group::From => "From",
+            Group::ListId => "List-Id",
+        };
+        let value = *mail.header(header).first()?;
+        // The part in angle brackets, if there's one
+        let id = match value.split_once('<') {
+            Some((_, rest)) => rest.split_once('>').map_or(rest, |(id, _)| id),
+            None => value,
+        };
+        Some(id.trim().to_lowercase()).filter(|id| !id.is_empty())
+    }
+}
+
+impl FromStr for Group {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "folder" => Ok(Group::Folder),
+            "from" => Ok(Group::From),
+            "list-id" => Ok(Group::ListId),
+            _ => bail!("Unknown group {}", s),
+        }
+    }
+}
+
+impl Display for Group {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Group::Folder => "folder",
+            Group::From => "from",
+            Group::ListId => "list-id",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Finds the `keep` newest messages of each group.
+///
+/// The result goes in the same order as the messages, with true for the ones to keep.
+pub fn newest<M: Message>(mails: &[M], group: Group, keep: usize) -> Vec<bool> {
+    let mut groups = HashMap::<_, Vec<usize>>::new();
+    for (idx, mail) in mails.iter().enumerate() {
+        if let Some(key) = group.key(mail) {
+            groups.entry(key).or_default().push(idx);
+        }
+    }
+    let mut newest = vec![false; mails.len()];
+    for mut members in groups.into_values() {
+        members.sort_by_key(|&idx| Reverse(mails[idx].date()));
+        for idx in members.into_iter().take(keep) {
+            newest[idx] = true;
+        }
+    }
+    newest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::select::mock::Mail;
+
+    fn mail(from: &'static str, list: Option<&'static str>, date: i64) -> Mail {
+        let mut headers = vec![("From", from)];
+        headers.extend(list.map(|list| ("List-Id", list)));
+        Mail {
+            headers,
+            date,
+            ..Mail::default()
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let m = mail(
+            "Alice <Alice@Example.org>",
+            Some("Rust <rust.example.org>"),
+            0,
+        );
+        assert_eq!(Group::Folder.key(&m).unwrap(), "");
+        assert_eq!(Group::From.key(&m).unwrap(), "alice@example.org");
+        assert_eq!(Group::ListId.key(&m).unwrap(), "rust.example.org");
+        let m = mail(" bob@example.org ", None, 0);
+        assert_eq!(Group::From.key(&m).unwrap(), "bob@example.org");
+        assert_eq!(Group::ListId.key(&m), None);
+    }
+
+    #[test]
+    fn keep_newest() {
+        let mails = [
+            mail("a@x", Some("<l1>"), 1),
+            mail("b@x", Some("<l1>"), 5),
+            mail("A <a@x>", Some("<l2>"), 3),
+            mail("a@x", None, 4),
+            mail("b@x", Some("<l1>"), 2),
+        ];
+        assert_eq!(
+            newest(&mails, Group::Folder, 2),
+            [false, true, false, true, false]
+        );
+        assert_eq!(
+            newest(&mails, Group::From, 1),
+            [false, true, false, true, false]
+        );
+        assert_eq!(
+            newest(&mails, Group::ListId, 1),
+            [false, true, true, false, false]
+        );
+        assert_eq!(newest(&mails, Group::Folder, 10), [true; 5]);
+    }
+}
@@ -0,0 +1,355 @@
+//! What happened in a run, in a machine readable form.
+//!
+//! Every message looked at gets a [`Decision`]: the [`Action`] taken and the [`Reason`] for it.
+//! The [`json`] function turns a whole [`Report`] into a single line of JSON.
+
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+
+use crate::{Counts, Report};
+
+/// The format of the report at the end of a run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// The summary in the log only.
+    Text,
+    /// A JSON object on the standard output, in addition to the log.
+    Json,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["text", "json"];
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => bail!("Unknown report format {}", s),
+        }
+    }
+}
+
+/// What was done with a message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Archived (or removed).
+    Archived,
+    /// Would be archived, but this is a dry run.
+    WouldArchive,
+    /// Failed to be archived, quarantined or purged.
+    Failed,
+    /// Left where it is.
+    Kept,
+    /// Moved to the quarantine.
+    Quarantined,
+    /// Would be quarantined, but this is a dry run.
+    WouldQuarantine,
+    /// Deleted after its grace period in the trash.
+    Purged,
+    /// Would be purged, but this is a dry run.
+    WouldPurge,
+    /// Deleted as a duplicate.
+    Deduplicated,
+    /// Would be deleted as a duplicate, but this is a dry run.
+    WouldDeduplicate,
+}
+
+impl Action {
+    /// Does the message leave the maildir (or would it, in a dry run)?
+    pub fn frees(&self) -> bool {
+        !matches!(self, Action::Kept | Action::Failed)
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Action::Archived => "archived",
+            Action::WouldArchive => "would-archive",
+            Action::Failed => "failed",
+            Action::Kept => "kept",
+            Action::Quarantined => "quarantined",
+            Action::WouldQuarantine => "would-quarantine",
+            Action::Purged => "purged",
+            Action::WouldPurge => "would-purge",
+            Action::Deduplicated => "deduplicated",
+            Action::WouldDeduplicate => "would-deduplicate",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Why a message was (or wasn't) archived.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Reason {
+    /// Old enough by the default rule.
+    Old,
+    /// Matched the --select expression.
+    Selected,
+    /// Not old enough yet.
+    TooNew,
+    /// Not seen yet (and --new not given).
+    Unseen,
+    /// Flagged as important.
+    Flagged,
+    /// Outside of the --before and --after dates.
+    OutOfRange,
+    /// Didn't match the --select expression.
+    NotSelected,
+    /// Old enough, but its thread has newer messages.
+    ThreadActive,
+    /// Old enough, but a message in its thread is flagged.
+    ThreadFlagged,
+    /// Old enough, but one of the newest messages kept by count.
+    Newest,
+    /// Old enough, but the maildir fits into --max-size without it.
+    WithinQuota,
+    /// Another copy is in the maildir or in the archive.
+    Duplicate,
+    /// Couldn't be parsed.
+    Unparseable,
+    /// Marked as trashed for longer than the grace period.
+    Trashed,
+    /// Marked as trashed, but still in the grace period.
+    InGrace,
+}
+
+impl Display for Reason {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Reason::Old => "old",
+            Reason::Selected => "selected",
+            Reason::TooNew => "too-new",
+            Reason::Unseen => "unseen",
+            Reason::Flagged => "flagged",
+            Reason::OutOfRange => "out-of-range",
+            Reason::NotSelected => "not-selected",
+            Reason::ThreadActive => "thread-active",
+            Reason::ThreadFlagged => "thread-flagged",
+            Reason::Newest => "newest",
+            Reason::WithinQuota => "within-quota",
+            Reason::Duplicate => "duplicate",
+            Reason::Unparseable => "unparseable",
+            Reason::Trashed => "trashed",
+            Reason::InGrace => "in-grace",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// The decision about one message.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub folder: String,
+    pub path: PathBuf,
+    /// Size of the message file, in bytes.
+    pub size: u64,
+    pub action: Action,
+    pub reason: Reason,
+}
+
+/// How a run went as a whole, ordered from the best to the worst.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Status {
+    /// No message was (or would be) archived, quarantined or purged.
+    NothingToDo,
+    /// Everything chosen was done.
+    Success,
+    /// Some of the messages failed to be archived, quarantined or purged.
+    Partial,
+    /// The run stopped early or some archives failed to be completed.
+    Fatal,
+}
+
+impl Status {
+    /// The exit code of the `decay` tool.
+    pub fn code(self) -> i32 {
+        match self {
+            Status::Success => 0,
+            Status::Fatal => 1,
+            Status::Partial => 2,
+            Status::NothingToDo => 3,
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Status::NothingToDo => "nothing-to-do",
+            Status::Success => "success",
+            Status::Partial => "partial",
+            Status::Fatal => "fatal",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Writes the string as a JSON string literal.
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn counts(out: &mut String, counts: &Counts) {
+    write!(
+        out,
+        "{{\"archived\":{},\"kept\":{},\"parse_errors\":{},\"move_errors\":{},\"quarantined\":{},\
+         \"purged\":{},\"duplicates\":{},\"pending\":{},\"freed\":{},\"reasons\":{{",
+        counts.archived,
+        counts.kept,
+        counts.parse_err,
+        counts.move_err,
+        counts.quarantined,
+        counts.purged,
+        counts.duplicates,
+        counts.pending,
+        counts.freed
+    )
+    .unwrap();
+    for (idx, (reason, count)) in counts.reasons.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        write!(out, "\"{}\":{}", reason, count).unwrap();
+    }
+    out.push_str("}}");
+}
+
+/// Formats the result of a run (of the named job) as one line of JSON.
+///
+/// The object has the `job` name (or null), the `status`, the `error` (or null), the counts of
+/// each of the `folders` and their `total`, and the `messages` with the decision about each of
+/// them (if they were collected). A run that failed has only the first three.
+pub fn json(job: Option<&str>, result: Result<&Report, &Error>) -> String {
+    let mut out = String::from("{\"job\":");
+    match job {
+        Some(job) => string(&mut out, job),
+        None => out.push_str("null"),
+    }
+    let (status, error) = match result {
+        Ok(report) => (report.status(), report.error.as_ref()),
+        Err(e) => (Status::Fatal, Some(e)),
+    };
+    write!(out, ",\"status\":\"{}\",\"error\":", status).unwrap();
+    match error {
+        Some(e) => string(&mut out, &format!("{:#}", e)),
+        None => out.push_str("null"),
+    }
+    if let Ok(report) = result {
+        out.push_str(",\"folders\":{");
+        for (idx, (folder, folder_counts)) in report.folders.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            string(&mut out, folder);
+            out.push(':');
+            counts(&mut out, folder_counts);
+        }
+        out.push_str("},\"total\":");
+        counts(&mut out, &report.total());
+        out.push_str(",\"messages\":[");
+        let decisions = report.decisions.as_deref().unwrap_or_default();
+        for (idx, decision) in decisions.iter().enumerate() {
+            if idx > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"folder\":");
+            string(&mut out, &decision.folder);
+            out.push_str(",\"path\":");
+            string(&mut out, &decision.path.to_string_lossy());
+            write!(
+                out,
+                ",\"size\":{},\"action\":\"{}\",\"reason\":\"{}\"}}",
+                decision.size, decision.action, decision.reason
+            )
+            .unwrap();
+        }
+        out.push(']');
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn decision(folder: &str, path: &str, action: Action, reason: Reason) -> Decision {
+        Decision {
+            folder: folder.to_owned(),
+            path: PathBuf::from(path),
+            size: 10,
+            action,
+            reason,
+        }
+    }
+
+    #[test]
+    fn report() {
+        let mut report = Report {
+            decisions: Some(Vec::new()),
+            ..Report::default()
+        };
+        let decisions = [
+            ("a.1", Action::Archived, Reason::Old),
+            ("a.2", Action::Kept, Reason::TooNew),
+            ("a.3", Action::Failed, Reason::Old),
+        ];
+        for (path, action, reason) in decisions {
+            report.record(decision("in\"box", path, action, reason));
+        }
+        assert_eq!(report.status(), Status::Partial);
+        let counts = "{\"archived\":1,\"kept\":1,\"parse_errors\":0,\"move_errors\":1,\
+            \"quarantined\":0,\"purged\":0,\"duplicates\":0,\"pending\":0,\"freed\":10,\"reasons\":{\"old\":2,\"too-new\":1}}";
+        let expected = format!(
+            "{{\"job\":\"lists\",\"status\":\"partial\",\"error\":null,\
+             \"folders\":{{\"in\\\"box\":{}}},\"total\":{},\"messages\":[\
+             {{\"folder\":\"in\\\"box\",\"path\":\"a.1\",\"size\":10,\"action\":\"archived\",\"reason\":\"old\"}},\
+             {{\"folder\":\"in\\\"box\",\"path\":\"a.2\",\"size\":10,\"action\":\"kept\",\"reason\":\"too-new\"}},\
+             {{\"folder\":\"in\\\"box\",\"path\":\"a.3\",\"size\":10,\"action\":\"failed\",\"reason\":\"old\"}}]}}",
+            counts, counts
+        );
+        assert_eq!(json(Some("lists"), Ok(&report)), expected);
+    }
+
+    #[test]
+    fn failed() {
+        let e = anyhow!("Broken\tpipe").context("Failed");
+        assert_eq!(
+            json(None, Err(&e)),
+            r#"{"job":null,"status":"fatal","error":"Failed: Broken\tpipe"}"#
+        );
+    }
+
+    #[test]
+    fn statuses() {
+        let mut report = Report::default();
+        assert_eq!(report.status(), Status::NothingToDo);
+        report.record(decision("x", "x.1", Action::Kept, Reason::Flagged));
+        assert_eq!(report.status(), Status::NothingToDo);
+        report.record(decision("x", "x.2", Action::WouldArchive, Reason::Old));
+        assert_eq!(report.status(), Status::Success);
+        report.error = Some(anyhow!("Disk full"));
+        assert_eq!(report.status(), Status::Fatal);
+    }
+}
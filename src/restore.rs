@@ -0,0 +1,306 @@
+//! Getting messages from an archive back into a maildir.
+
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use log::{error, info};
+use maildir::Maildir;
+use mailparse::{dateparse, parse_mail, MailHeaderMap};
+
+use crate::archive::{Archive, Compression};
+use crate::date;
+use crate::deliver;
+use crate::mbox;
+use crate::select::{self, Expr, Message};
+
+/// Which messages to restore.
+///
+/// All the set conditions must hold. With none set, everything is restored. A message without a
+/// usable `Date` header is outside of any `before` or `after` bound.
+#[derive(Debug, Default)]
+pub struct Filter<'a> {
+    /// Any of these Message-IDs (with or without the angle brackets).
+    pub message_ids: &'a [String],
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub select: Option<&'a Expr>,
+}
+
+/// A message read from the archive.
+struct Archived {
+    raw: Vec<u8>,
+    flags: String,
+    headers: Vec<(String, String)>,
+    date: Option<i64>,
+    attachment: bool,
+}
+
+impl Archived {
+    fn new(raw: &[u8]) -> Result<Self, Error> {
+        let (raw, flags) = mbox::take_status(raw);
+        let parsed = parse_mail(&raw).context("Can't parse mail")?;
+        let headers = parsed.get_headers();
+        let date = headers
+            .get_first_value("Date")
+            .and_then(|date| dateparse(&date).ok())
+            .and_then(|date| date::known(date).ok());
+        let headers = headers
+            .into_iter()
+            .map(|h| (h.get_key(), h.get_value()))
+            .collect();
+        let attachment = select::has_attachment(&parsed);
+        Ok(Self {
+            flags,
+            headers,
+            date,
+            attachment,
+            raw,
+        })
+    }
+
+    fn describe(&self) -> String {
+        let first = |name| self.header(name).first().copied().unwrap_or_default();
+        format!(
+            "{}/{}/{}",
+            first("Message-ID"),
+            first("Date"),
+            first("Subject")
+        )
+    }
+}
+
+impl Message for Archived {
+    fn header(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn size(&self) -> u64 {
+        self.raw.len() as u64
+    }
+
+    /// Without a usable date the message counts as being from 1970.
+    fn date(&self) -> i64 {
+        self.date.unwrap_or_default()
+    }
+
+    fn flag(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+
+    /// The archive doesn't keep which messages were in `new`, so the unread ones count as new.
+    fn is_new(&self) -> bool {
+        !self.flag('S')
+    }
+
+    fn has_attachment(&self) -> bool {
+        self.attachment
+    }
+}
+
+fn strip_id(id: &str) -> &str {
+    id.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+impl Filter<'_> {
+    fn matches(&self, mail: &Archived, now: i64) -> bool {
+        let id_ok = self.message_ids.is_empty()
+            || mail.header("Message-ID").iter().any(|id| {
+                self.message_ids
+                    .iter()
+                    .any(|wanted| strip_id(wanted) == strip_id(id))
+            });
+        id_ok
+            && self
+                .before
+                .map_or(true, |before| mail.date.is_some_and(|date| date < before))
+            && self
+                .after
+                .map_or(true, |after| mail.date.is_some_and(|date| date >= after))
+            && self.select.map_or(true, |select| select.matches(mail, now))
+    }
+}
+
+/// Delivers the messages from the archive matching the filter into the target maildir.
+///
+/// Unless `confirm` is set, only lists them. The flags are reconstructed from the `Status` and
+/// `X-Status` headers. The archive is read one message at a time. Returns how many messages were
+/// (or would be) restored.
+pub fn restore(
+    archive: &Path,
+    compression: Compression,
+    format: mbox::Format,
+    target: &Path,
+    filter: &Filter,
+    now: i64,
+    confirm: bool,
+) -> Result<usize, Error> {
+    compression.check_program()?;
+    let reader = BufReader::new(Archive::reader(archive, compression)?);
+    if confirm {
+        deliver::prepare(target)?;
+    }
+    let maildir = Maildir::from(target.to_owned());
+    let mut restored = 0;
+    for (idx, raw) in mbox::Reader::new(reader, format).enumerate() {
+        let raw = raw.with_context(|| format!("Failed to read {}", archive.display()))?;
+        let mail = match Archived::new(&raw) {
+            Ok(mail) => mail,
+            Err(e) => {
+                error!(
+                    "{:?}",
+                    e.context(format!("Failed to parse message {}", idx))
+                );
+                continue;
+            }
+        };
+        if !filter.matches(&mail, now) {
+            continue;
+        }
+        info!("Restore {}", mail.describe());
+        if confirm {
+            maildir
+                .store_cur_with_flags(&mail.raw, &mail.flags)
+                .with_context(|| format!("Failed to store {}", mail.describe()))?;
+        }
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::testdir::TempDir;
+
+    const MAILS: &[(&str, &str)] = &[
+        (
+            "Message-ID: <1@x>\nDate: Mon, 1 Jan 2024 10:00:00 +0000\n\nFirst\n",
+            "S",
+        ),
+        (
+            "Message-ID: <2@x>\nDate: Thu, 1 Feb 2024 10:00:00 +0000\n\nSecond\n",
+            "FRS",
+        ),
+        ("Message-ID: <3@x>\n\nNo date\n", ""),
+        ("Message-ID: <4@x>\nDate: someday\n\nBroken date\n", "S"),
+    ];
+
+    /// Writes the messages into an mbox archive.
+    fn archive(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("archive.mbox");
+        let mut file = File::create(&path).unwrap();
+        for (raw, flags) in MAILS {
+            mbox::write(
+                &mut file,
+                raw.as_bytes(),
+                flags,
+                UNIX_EPOCH,
+                mbox::Format::Mboxrd,
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    /// The contents of the restored messages with their flags, sorted.
+    fn restored(maildir: &Path) -> Vec<(String, String)> {
+        let mut mails = fs::read_dir(maildir.join("cur"))
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_str().unwrap();
+                let flags = name.rsplit(":2,").next().unwrap().to_owned();
+                (fs::read_to_string(&path).unwrap(), flags)
+            })
+            .collect::<Vec<_>>();
+        mails.sort();
+        mails
+    }
+
+    fn run(dir: &TempDir, filter: &Filter, confirm: bool) -> (usize, Vec<(String, String)>) {
+        let target = dir.path().join("restored");
+        let _ = fs::remove_dir_all(&target);
+        let count = restore(
+            &archive(dir),
+            Compression::None,
+            mbox::Format::Mboxrd,
+            &target,
+            filter,
+            0,
+            confirm,
+        )
+        .unwrap();
+        let mails = if target.exists() {
+            restored(&target)
+        } else {
+            Vec::new()
+        };
+        (count, mails)
+    }
+
+    fn expected(idx: &[usize]) -> Vec<(String, String)> {
+        idx.iter()
+            .map(|&i| (MAILS[i].0.to_owned(), MAILS[i].1.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn everything() {
+        let dir = TempDir::new("restore-all");
+        assert_eq!(
+            (4, expected(&[0, 1, 2, 3])),
+            run(&dir, &Filter::default(), true)
+        );
+        // Only listed without confirm
+        assert_eq!((4, Vec::new()), run(&dir, &Filter::default(), false));
+    }
+
+    #[test]
+    fn by_date() {
+        let dir = TempDir::new("restore-dates");
+        let middle = date::parse("2024-01-15").unwrap();
+        let before = Filter {
+            before: Some(middle),
+            ..Filter::default()
+        };
+        assert_eq!((1, expected(&[0])), run(&dir, &before, true));
+        let after = Filter {
+            after: Some(middle),
+            ..Filter::default()
+        };
+        assert_eq!((1, expected(&[1])), run(&dir, &after, true));
+    }
+
+    #[test]
+    fn by_id_and_select() {
+        let dir = TempDir::new("restore-select");
+        let ids = ["2@x".to_owned(), "<3@x>".to_owned()];
+        let filter = Filter {
+            message_ids: &ids,
+            ..Filter::default()
+        };
+        assert_eq!((2, expected(&[1, 2])), run(&dir, &filter, true));
+        // The unread message is the new one
+        let new = "new".parse().unwrap();
+        let filter = Filter {
+            select: Some(&new),
+            ..Filter::default()
+        };
+        assert_eq!((1, expected(&[2])), run(&dir, &filter, true));
+        let flagged = "flagged".parse().unwrap();
+        let filter = Filter {
+            select: Some(&flagged),
+            ..Filter::default()
+        };
+        assert_eq!((1, expected(&[1])), run(&dir, &filter, true));
+    }
+}
@@ -0,0 +1,448 @@
+//! Selection expressions, deciding which messages to archive.
+//!
+//! An expression combines conditions with `and` (`&`), `or` (`|`), `not` (`!`) and parentheses.
+//! The conditions are:
+//!
+//! * `HEADER ~ "regex"`: Any of the headers of that name (eg. `from`, `to`, `list-id`,
+//!   `subject`) matches the (case insensitive) regular expression.
+//! * `size > 1M`: Size of the message, with optional `k`, `M` or `G` suffix. Any of `<`, `<=`,
+//!   `>` and `>=` can be used.
+//! * `age > 14`: Age of the message in days, or with a unit (`36h`, `6w`, `18mo`). Ages in whole
+//!   days (`14`, `2w`) compare the age in whole days, so a message 14 and a half days old is 14
+//!   days old. The other ones compare the exact time.
+//! * `date < 2024-01-01`: Date of the message (in UTC, time can be added as `2024-01-01T12:00`).
+//! * `seen`, `flagged`, `replied`, `passed`, `draft`, `trashed`: The maildir flags.
+//! * `new`: The message is in the `new` subdirectory.
+//! * `attachment`: The message has an attachment.
+//!
+//! For example `list-id ~ "." and age > 14 or not list-id ~ "." and age > 730`.
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error};
+use mailparse::{DispositionType, ParsedMail};
+use regex::{Regex, RegexBuilder};
+
+use crate::date::{self, Age, DAY};
+
+/// Properties of a message the expressions can look at.
+pub trait Message {
+    /// All the values of headers with the given name (case insensitive).
+    fn header(&self, name: &str) -> Vec<&str>;
+    /// Size in bytes.
+    fn size(&self) -> u64;
+    /// The date of the message, as unix timestamp.
+    fn date(&self) -> i64;
+    /// Is the maildir flag (`S`, `F`, `R`, ...) set?
+    fn flag(&self, flag: char) -> bool;
+    /// Is the message in the `new` subdirectory?
+    fn is_new(&self) -> bool;
+    fn has_attachment(&self) -> bool;
+}
+
+/// Does the message (or any of its parts) contain an attachment?
+pub fn has_attachment(mail: &ParsedMail) -> bool {
+    let disposition = mail.get_content_disposition();
+    disposition.disposition == DispositionType::Attachment
+        || disposition.params.contains_key("filename")
+        || mail.subparts.iter().any(has_attachment)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Match,
+    Cmp(Ordering, bool),
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ':'
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' => Token::Not,
+            '&' => Token::And,
+            '|' => Token::Or,
+            '~' => Token::Match,
+            '<' | '>' => {
+                let ord = if c == '<' {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+                let eq = chars.peek() == Some(&'=');
+                if eq {
+                    chars.next();
+                }
+                Token::Cmp(ord, eq)
+            }
+            '"' | '\'' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' && chars.peek() == Some(&'"') => {
+                            s.push(chars.next().unwrap())
+                        }
+                        Some(ch) => s.push(ch),
+                        None => bail!("Unterminated string"),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if is_word(c) => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !is_word(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.to_ascii_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                }
+            }
+            c => bail!("Unexpected {}", c),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Parses a size in bytes, with an optional K, M or G (binary) suffix.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let (num, mult) = match s.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'm') | Some(b'M') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'g') | Some(b'G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let num: u64 = num.parse().with_context(|| format!("Invalid size {}", s))?;
+    num.checked_mul(mult)
+        .with_context(|| format!("Size {} is too big", s))
+}
+
+/// A comparison operator (`<`, `>=`, ...).
+#[derive(Clone, Debug)]
+pub struct Cmp {
+    ord: Ordering,
+    eq: bool,
+}
+
+impl Cmp {
+    /// Does the comparison hold for the given ordering of the value and the constant?
+    fn holds(&self, ord: Ordering) -> bool {
+        ord == self.ord || (self.eq && ord == Ordering::Equal)
+    }
+}
+
+/// A parsed selection expression.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Header(String, Regex),
+    Size(Cmp, u64),
+    Age(Cmp, Age),
+    Date(Cmp, i64),
+    Flag(char),
+    New,
+    Attachment,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, Error> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.atom()
+        }
+    }
+
+    fn cmp(&mut self, what: &str) -> Result<(Cmp, String), Error> {
+        match (self.next(), self.next()) {
+            (Some(Token::Cmp(ord, eq)), Some(Token::Word(value))) => Ok((Cmp { ord, eq }, value)),
+            _ => bail!("Expected {} to be compared with a value", what),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, Error> {
+        let word = match self.next() {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                ensure!(self.next() == Some(Token::Close), "Missing )");
+                return Ok(expr);
+            }
+            Some(Token::Word(word)) => word,
+            Some(token) => bail!("Unexpected {:?}", token),
+            None => bail!("Unexpected end of expression"),
+        };
+        let flag = |c| Ok(Expr::Flag(c));
+        match word.to_ascii_lowercase().as_str() {
+            "seen" => flag('S'),
+            "flagged" => flag('F'),
+            "replied" => flag('R'),
+            "passed" => flag('P'),
+            "draft" => flag('D'),
+            "trashed" => flag('T'),
+            "new" => Ok(Expr::New),
+            "attachment" => Ok(Expr::Attachment),
+            "size" => {
+                let (cmp, value) = self.cmp("size")?;
+                Ok(Expr::Size(cmp, parse_size(&value)?))
+            }
+            "age" => {
+                let (cmp, value) = self.cmp("age")?;
+                Ok(Expr::Age(cmp, value.parse()?))
+            }
+            "date" => {
+                let (cmp, value) = self.cmp("date")?;
+                Ok(Expr::Date(cmp, date::parse(&value)?))
+            }
+            _ => match (self.next(), self.next()) {
+                (Some(Token::Match), Some(Token::Str(re))) => {
+                    let re = RegexBuilder::new(&re)
+                        .case_insensitive(true)
+                        .build()
+                        .with_context(|| format!("Invalid regex for {}", word))?;
+                    Ok(Expr::Header(word, re))
+                }
+                _ => bail!("Expected {} ~ \"regex\"", word),
+            },
+        }
+    }
+}
+
+impl Expr {
+    /// Does the message match the expression, at the time `now`?
+    pub fn matches(&self, mail: &dyn Message, now: i64) -> bool {
+        match self {
+            Expr::Or(a, b) => a.matches(mail, now) || b.matches(mail, now),
+            Expr::And(a, b) => a.matches(mail, now) && b.matches(mail, now),
+            Expr::Not(e) => !e.matches(mail, now),
+            Expr::Header(name, re) => mail.header(name).iter().any(|v| re.is_match(v)),
+            Expr::Size(cmp, size) => cmp.holds(mail.size().cmp(size)),
+            Expr::Age(cmp, age) => match age.whole_days() {
+                // Age in whole days, a message from the future is 0 days old
+                Some(days) => cmp.holds(((now - mail.date()) / DAY).max(0).cmp(&days)),
+                // The older the message, the further before the cutoff it is
+                None => cmp.holds(age.before(now).cmp(&mail.date())),
+            },
+            Expr::Date(cmp, date) => cmp.holds(mail.date().cmp(date)),
+            Expr::Flag(flag) => mail.flag(*flag),
+            Expr::New => mail.is_new(),
+            Expr::Attachment => mail.has_attachment(),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let parse = || {
+            let mut parser = Parser {
+                tokens: tokenize(s)?,
+                pos: 0,
+            };
+            let expr = parser.or()?;
+            ensure!(parser.peek().is_none(), "Garbage at the end");
+            Ok(expr)
+        };
+        // The causes are lost when shown as an error of a command line option
+        parse().map_err(|e: Error| anyhow!("Invalid expression {}: {:#}", s, e))
+    }
+}
+
+/// A made up message for the tests of the modules deciding about messages.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::Message;
+
+    #[derive(Debug, Default)]
+    pub struct Mail {
+        pub headers: Vec<(&'static str, &'static str)>,
+        pub size: u64,
+        pub date: i64,
+        pub flags: &'static str,
+    }
+
+    impl Mail {
+        pub fn with_headers(headers: Vec<(&'static str, &'static str)>) -> Self {
+            Self {
+                headers,
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Message for Mail {
+        fn header(&self, name: &str) -> Vec<&str> {
+            self.headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| *v)
+                .collect()
+        }
+        fn size(&self) -> u64 {
+            self.size
+        }
+        fn date(&self) -> i64 {
+            self.date
+        }
+        fn flag(&self, flag: char) -> bool {
+            self.flags.contains(flag)
+        }
+        fn is_new(&self) -> bool {
+            false
+        }
+        fn has_attachment(&self) -> bool {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::Mail;
+    use super::*;
+
+    // 2023-11-14 22:13:20
+    const NOW: i64 = 1_700_000_000;
+
+    fn matches(expr: &str, mail: &Mail) -> bool {
+        expr.parse::<Expr>().unwrap().matches(mail, NOW)
+    }
+
+    #[test]
+    fn lists_and_personal() {
+        let expr = "list-id ~ '.' and age > 14 or not list-id ~ \".\" and age > 730";
+        let list = Mail {
+            headers: vec![("List-Id", "<rust.lists>")],
+            size: 100,
+            date: NOW - 20 * DAY,
+            flags: "S",
+        };
+        let personal = Mail {
+            headers: vec![("From", "Someone <a@example.com>")],
+            size: 100,
+            date: NOW - 20 * DAY,
+            flags: "S",
+        };
+        assert!(matches(expr, &list));
+        assert!(!matches(expr, &personal));
+    }
+
+    #[test]
+    fn conditions() {
+        let mail = Mail {
+            headers: vec![("From", "Someone <A@Example.com>")],
+            size: 2048,
+            date: NOW - 30 * DAY,
+            flags: "RS",
+        };
+        assert!(matches("from ~ 'a@example'", &mail));
+        assert!(!matches("to ~ '.'", &mail));
+        assert!(matches("size >= 2k & size < 1M", &mail));
+        assert!(!matches("size > 2k", &mail));
+        assert!(matches("age >= 30 & age <= 30", &mail));
+        assert!(matches("age > 4w and age < 1mo", &mail));
+        assert!(matches(
+            "date < 2023-10-16 and date >= 2023-10-15T22:13:20",
+            &mail
+        ));
+        assert!(matches("replied & seen & !(flagged | draft)", &mail));
+    }
+
+    #[test]
+    fn whole_days() {
+        let mail = Mail {
+            headers: Vec::new(),
+            size: 100,
+            date: NOW - 14 * DAY - DAY / 2,
+            flags: "",
+        };
+        // 14 and a half days is 14 days
+        assert!(!matches("age > 14", &mail));
+        assert!(matches("age >= 14 & age <= 14", &mail));
+        assert!(!matches("age > 2w", &mail));
+        assert!(matches("age < 15", &mail));
+        // Other units compare the exact time
+        assert!(matches("age > 347h", &mail));
+        assert!(!matches("age > 349h", &mail));
+        // From the future
+        let future = Mail {
+            date: NOW + DAY,
+            ..mail
+        };
+        assert!(matches("age <= 0", &future));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("".parse::<Expr>().is_err());
+        assert!("seen and".parse::<Expr>().is_err());
+        assert!("(seen".parse::<Expr>().is_err());
+        assert!("size > big".parse::<Expr>().is_err());
+        assert!("size > 18014398509481984k".parse::<Expr>().is_err());
+        assert!(parse_size("17179869184G").is_err());
+        assert_eq!(Some(u64::MAX), parse_size("18446744073709551615").ok());
+        assert!("age > 3m".parse::<Expr>().is_err());
+        assert!("date > 2023-02-29".parse::<Expr>().is_err());
+        assert!("from ~ '('".parse::<Expr>().is_err());
+        assert!("from".parse::<Expr>().is_err());
+        assert!("seen flagged".parse::<Expr>().is_err());
+    }
+}
@@ -0,0 +1,895 @@
+//! Where the archived messages go.
+//!
+//! A run hands every message it decided to archive to a [`Sink`], which stores the message and
+//! removes it from the maildir. There are sinks for mbox files ([`Mbox`]), other maildirs
+//! ([`Maildir`]), directories of `.eml` files ([`Eml`]) and for just removing the messages
+//! ([`Remove`]). Other destinations can be plugged in by implementing the trait and passing it to
+//! [`Run::with_sink`](crate::Run::with_sink).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Error};
+use log::{error, warn};
+
+use crate::archive::{Archive, Compression};
+use crate::journal::Journal;
+use crate::mbox;
+use crate::template::Template;
+use crate::trash::{self, Trash};
+use crate::verify::{self, Fingerprint};
+use crate::{MailInfo, Options};
+
+/// Receives the messages once a sink is done with them, together with whether they were archived.
+pub type Done<'a> = dyn FnMut(MailInfo, bool) + 'a;
+
+/// A destination of the archived messages.
+pub trait Sink {
+    /// Stores the message and removes it from the maildir.
+    ///
+    /// The sink may hold the message back to store it later together with others. Once its fate
+    /// is known, the message is passed to `done`. Failures of single messages are logged by the
+    /// sink and only errors that should stop the whole run are returned.
+    fn put(&mut self, mail: MailInfo, done: &mut Done) -> Result<(), Error>;
+
+    /// Stores the messages held back and closes the sink at the end of the run.
+    ///
+    /// Messages might still be passed to `done` even if this fails.
+    fn finish(&mut self, _done: &mut Done) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Creates the sink the options ask for.
+///
+/// The `now` is the time the removed messages are trashed at.
+pub fn from_options<'a>(opts: &'a Options, now: i64) -> Result<Box<dyn Sink + 'a>, Error> {
+    let sink: Box<dyn Sink + 'a> = match (&opts.archive, &opts.archive_maildir, &opts.archive_eml) {
+        (Some(archive), _, _) => Box::new(Mbox::new(opts, archive)?),
+        (_, Some(target), _) => Box::new(Maildir::new(target)),
+        (_, _, Some(dir)) => Box::new(Eml::new(opts, dir)?),
+        (None, None, None) => Box::new(Remove::new(opts, now)),
+    };
+    Ok(sink)
+}
+
+/// Logs the error of a single message and passes the message on.
+fn report(mail: MailInfo, result: Result<(), Error>, done: &mut Done) {
+    if let Err(e) = &result {
+        error!("{:?}", e);
+    }
+    done(mail, result.is_ok());
+}
+
+/// The message as it goes into the archive, with the attachments handled by the options.
+///
+/// The `attachments` is the directory the attachments are extracted to.
+fn content(mail: &MailInfo, opts: &Options, attachments: &Path) -> Result<Vec<u8>, Error> {
+    opts.attachments
+        .apply(mail.raw()?, opts.attachment_size, attachments)
+        .context("Failed to process attachments")
+}
+
+/// Writes the message in the mbox format, with the attachments handled by the options.
+///
+/// Returns the message as it went into the archive, before the mbox formatting.
+fn write_mbox(
+    mail: &MailInfo,
+    dest: &mut dyn Write,
+    opts: &Options,
+    archive: &Path,
+) -> Result<Vec<u8>, Error> {
+    let mut dir = archive.as_os_str().to_owned();
+    dir.push(".attachments");
+    let data = content(mail, opts, Path::new(&dir))?;
+    mbox::write(
+        dest,
+        &data,
+        &mail.flags,
+        SystemTime::now(),
+        opts.mbox_format,
+    )
+    .context("Failed to output email")?;
+    Ok(data)
+}
+
+/// How many archives are kept open at once, the least recently used one is closed to open another.
+const OPEN_ARCHIVES: usize = 16;
+
+/// Appends the messages to mbox files.
+///
+/// Takes care of the journal, verification and single stream rewriting as set in the options. A
+/// single stream archive is rewritten once it is closed, when all its batches are in. Journals and
+/// temporary files left by an interrupted run are dealt with right when the sink is created.
+/// Extracted attachments go into the `.attachments` directory next to each archive.
+pub struct Mbox<'a> {
+    opts: &'a Options,
+    template: Template,
+    /// Does the template produce different archives for different messages?
+    templated: bool,
+    targets: BTreeMap<PathBuf, Target>,
+    /// Counter for finding the least recently used target.
+    uses: u64,
+}
+
+impl<'a> Mbox<'a> {
+    /// The archive path may contain the placeholders of [`Options::archive`].
+    pub fn new(opts: &'a Options, archive: &Path) -> Result<Self, Error> {
+        let template = Template::parse(archive);
+        recover(opts, &template)?;
+        Ok(Self {
+            opts,
+            templated: template.is_templated(),
+            template,
+            targets: BTreeMap::new(),
+            uses: 0,
+        })
+    }
+
+    /// Opens one of the archives.
+    fn open_target(&self, path: &Path) -> Result<Target, Error> {
+        let opts = self.opts;
+        if self.templated {
+            if let Some(parent) = path.parent().filter(|p| *p != Path::new("")) {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+        }
+        let journal = if opts.journal {
+            Some(Journal::for_archive(path))
+        } else {
+            None
+        };
+        let archive = Archive::open(path, opts.compression(path), opts.compression_level)
+            .context("Failed to open the destination")?;
+        Ok(Target {
+            archive,
+            journal,
+            batch: Vec::new(),
+            unsynced: 0,
+            used: 0,
+        })
+    }
+}
+
+/// Finishes (or rolls back) the batches of an interrupted run and removes its temporary files.
+///
+/// Done for all the archives the template could have expanded to, before any message is archived.
+/// Without confirmation, they are only reported.
+fn recover(opts: &Options, template: &Template) -> Result<(), Error> {
+    for journal in template.existing(".journal")? {
+        let archive = journal.with_extension("");
+        if opts.confirm {
+            Journal::for_archive(&archive).recover(&archive, opts.rollback)?;
+        } else {
+            warn!(
+                "Found journal {} of an interrupted run, it'll be recovered with --confirm",
+                journal.display()
+            );
+        }
+    }
+    for tmp in template.existing(".decay-tmp")? {
+        if opts.confirm {
+            Archive::remove_stale(&tmp.with_extension(""))?;
+        } else {
+            warn!(
+                "Found {} of an interrupted run, it'll be removed with --confirm",
+                tmp.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Sink for Mbox<'_> {
+    fn put(&mut self, mail: MailInfo, done: &mut Done) -> Result<(), Error> {
+        let opts = self.opts;
+        let path = self.template.expand(mail.date_resolved, &mail.folder);
+        if !self.targets.contains_key(&path) {
+            if self.targets.len() >= OPEN_ARCHIVES {
+                let oldest = self
+                    .targets
+                    .iter()
+                    .min_by_key(|(_, target)| target.used)
+                    .map(|(path, _)| path.clone())
+                    .expect("Full pool of targets");
+                let target = self.targets.remove(&oldest).expect("Target just found");
+                target.close(&oldest, opts, done)?;
+            }
+            let target = self.open_target(&path)?;
+            self.targets.insert(path.clone(), target);
+        }
+        self.uses += 1;
+        let target = self.targets.get_mut(&path).expect("Target just opened");
+        target.used = self.uses;
+        if opts.batched() {
+            target.batch.push(mail);
+            if opts.batch_full(target.batch.len()) {
+                target.flush(opts, done)?;
+            }
+            return Ok(());
+        }
+        let archived = write_mbox(&mail, &mut target.archive, opts, &path)
+            .with_context(|| format!("Failed to move mail {}", mail))
+            .and_then(|_| mail.delete())
+            .map(|()| target.unsynced += 1);
+        report(mail, archived, done);
+        Ok(())
+    }
+
+    fn finish(&mut self, done: &mut Done) -> Result<(), Error> {
+        let mut finished = Ok(());
+        for (path, target) in std::mem::take(&mut self.targets) {
+            if let Err(e) = target.close(&path, self.opts, done) {
+                if let Err(previous) = std::mem::replace(&mut finished, Err(e)) {
+                    error!("{:?}", previous);
+                }
+            }
+        }
+        finished
+    }
+}
+
+/// An archive opened during the run.
+struct Target {
+    archive: Archive,
+    journal: Option<Journal>,
+    /// Messages waiting to be written, in the batched mode.
+    batch: Vec<MailInfo>,
+    /// Deleted messages that might be still only in the buffers of the archive.
+    unsynced: usize,
+    /// When the target was last used, by the counter of the sink.
+    used: u64,
+}
+
+impl Target {
+    /// Writes the pending batch.
+    fn flush(&mut self, opts: &Options, done: &mut Done) -> Result<(), Error> {
+        let deleted = archive_batch(&self.batch, &mut self.archive, self.journal.as_ref(), opts)?;
+        for (mail, deleted) in self.batch.drain(..).zip(deleted) {
+            done(mail, deleted);
+        }
+        Ok(())
+    }
+
+    /// Writes the pending batch and completes the archive at the `path`.
+    fn close(mut self, path: &Path, opts: &Options, done: &mut Done) -> Result<(), Error> {
+        if !self.batch.is_empty() {
+            self.flush(opts, done)?;
+        }
+        if let Err(e) = self.archive.finish() {
+            if self.unsynced > 0 {
+                return Err(e.context(format!(
+                    "{} messages were already deleted and may be missing from {}",
+                    self.unsynced,
+                    path.display()
+                )));
+            }
+            return Err(e);
+        }
+        let compression = opts.compression(path);
+        if opts.single_stream && compression != Compression::None {
+            Archive::rewrite(path, compression, opts.compression_level)
+                .and_then(Archive::finish)
+                .with_context(|| format!("Failed to rewrite {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A batch written to the archive, with the messages still in the maildir.
+struct Written {
+    /// Length of the archive before the batch.
+    start: u64,
+    /// Length of the archive with the batch.
+    len: u64,
+    /// Which of the messages made it into the archive.
+    stored: Vec<bool>,
+    /// Fingerprints of the stored messages, when verifying.
+    expected: Vec<Fingerprint>,
+}
+
+/// Writes a batch of messages to the archive and deletes them once they are safely on the disk.
+///
+/// Messages that can't be read are left out of the batch. Failing to write the archive is fatal
+/// and leaves the journal behind, so the next run rolls the archive back. If the verification
+/// fails, the batch is removed from the archive and nothing is deleted. Failures to delete
+/// individual messages are only logged. Returns which messages were deleted.
+fn archive_batch(
+    batch: &[MailInfo],
+    archive: &mut Archive,
+    journal: Option<&Journal>,
+    opts: &Options,
+) -> Result<Vec<bool>, Error> {
+    let written = write_batch(batch, archive, journal, opts)?;
+    settle_batch(batch, written, archive, journal, opts)
+}
+
+/// Writes the batch to the archive and syncs it to the disk.
+fn write_batch(
+    batch: &[MailInfo],
+    archive: &mut Archive,
+    journal: Option<&Journal>,
+    opts: &Options,
+) -> Result<Written, Error> {
+    let start = archive.durable_len();
+    if let Some(journal) = journal {
+        journal.begin(start)?;
+    }
+    let mut stored = Vec::with_capacity(batch.len());
+    let mut expected = Vec::new();
+    for mail in batch {
+        let mut data = Vec::new();
+        let prepared = write_mbox(mail, &mut data, opts, archive.path()).and_then(|content| {
+            if opts.verify {
+                Fingerprint::of(&content, opts.mbox_format).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+        match prepared {
+            Ok(fingerprints) => {
+                archive.write_all(&data).with_context(|| {
+                    format!(
+                        "Failed to write mail {} to {}",
+                        mail,
+                        archive.path().display()
+                    )
+                })?;
+                expected.extend(fingerprints);
+                stored.push(true);
+            }
+            Err(e) => {
+                error!("{:?}", e.context(format!("Failed to move mail {}", mail)));
+                stored.push(false);
+            }
+        }
+    }
+    let len = archive
+        .checkpoint()
+        .with_context(|| format!("Failed to store batch in {}", archive.path().display()))?;
+    Ok(Written {
+        start,
+        len,
+        stored,
+        expected,
+    })
+}
+
+/// Verifies the written batch and deletes the stored messages.
+fn settle_batch(
+    batch: &[MailInfo],
+    written: Written,
+    archive: &mut Archive,
+    journal: Option<&Journal>,
+    opts: &Options,
+) -> Result<Vec<bool>, Error> {
+    if opts.verify {
+        let verified = archive
+            .read_from(written.start)
+            .and_then(|data| Fingerprint::all(&data, opts.mbox_format))
+            .and_then(|found| verify::check(&found, &written.expected));
+        if let Err(e) = verified {
+            error!("{:?}", e.context("Verification of the archive failed"));
+            archive.rollback(written.start)?;
+            if let Some(journal) = journal {
+                journal.clear()?;
+            }
+            return Ok(vec![false; batch.len()]);
+        }
+    }
+
+    let stored = || {
+        batch
+            .iter()
+            .zip(&written.stored)
+            .filter(|(_, &stored)| stored)
+            .map(|(mail, _)| mail)
+    };
+    if let Some(journal) = journal {
+        journal.commit(written.len, stored().map(|mail| mail.path.as_path()))?;
+    }
+
+    let deleted = batch
+        .iter()
+        .zip(&written.stored)
+        .map(|(mail, &stored)| {
+            stored
+                && match fs::remove_file(&mail.path) {
+                    Ok(()) => true,
+                    Err(e) if e.kind() == ErrorKind::NotFound => true,
+                    Err(e) => {
+                        error!("Failed to delete mail {}: {}", mail, e);
+                        false
+                    }
+                }
+        })
+        .collect();
+    let dirs = stored().filter_map(|mail| mail.path.parent());
+    for dir in dirs.collect::<BTreeSet<_>>() {
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))?;
+    }
+    if let Some(journal) = journal {
+        journal.clear()?;
+    }
+
+    Ok(deleted)
+}
+
+/// Moves the messages into another maildir, keeping their flags.
+pub struct Maildir {
+    dir: PathBuf,
+}
+
+impl Maildir {
+    /// The maildir is expected to exist already.
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+}
+
+impl Sink for Maildir {
+    fn put(&mut self, mail: MailInfo, done: &mut Done) -> Result<(), Error> {
+        let moved = mail
+            .move_to(&self.dir)
+            .with_context(|| format!("Failed to move mail {}", mail));
+        report(mail, moved, done);
+        Ok(())
+    }
+}
+
+/// Saves each message as a separate `ID.eml` file into a directory.
+///
+/// The directory may contain the placeholders of [`Options::archive`], the directories are created
+/// as needed. Extracted attachments go into its `attachments` subdirectory.
+pub struct Eml<'a> {
+    opts: &'a Options,
+    template: Template,
+}
+
+impl<'a> Eml<'a> {
+    pub fn new(opts: &'a Options, dir: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            opts,
+            template: Template::parse(dir),
+        })
+    }
+
+    fn save(&self, mail: &MailInfo) -> Result<(), Error> {
+        let dir = self.template.expand(mail.date_resolved, &mail.folder);
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(format!("{}.eml", mail.id));
+        let data = content(mail, self.opts, &dir.join("attachments"))?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        File::open(&dir)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))?;
+        mail.delete()
+    }
+}
+
+impl Sink for Eml<'_> {
+    fn put(&mut self, mail: MailInfo, done: &mut Done) -> Result<(), Error> {
+        let saved = self
+            .save(&mail)
+            .with_context(|| format!("Failed to move mail {}", mail));
+        report(mail, saved, done);
+        Ok(())
+    }
+}
+
+/// Removes the messages, right away or into the trash as set in the options.
+pub struct Remove {
+    trash: Option<Trash>,
+    flag: bool,
+    now: i64,
+}
+
+impl Remove {
+    /// The `now` is recorded as the time of trashing in the undo log.
+    pub fn new(opts: &Options, now: i64) -> Self {
+        Self {
+            trash: opts.trash.as_deref().map(Trash::new),
+            flag: opts.trash_flag,
+            now,
+        }
+    }
+}
+
+impl Sink for Remove {
+    fn put(&mut self, mail: MailInfo, done: &mut Done) -> Result<(), Error> {
+        let removed = match (&self.trash, self.flag) {
+            (Some(trash), _) => trash.put(&mail.path, &mail.id, &mail.flags, self.now),
+            (None, true) => trash::flag(&mail.path, &mail.id, &mail.flags, self.now),
+            (None, false) => mail.delete(),
+        };
+        report(mail, removed, done);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::dedup;
+    use crate::folders::Folder;
+    use crate::report::{self, Action, Reason};
+    use crate::testdir::{self, TempDir};
+    use crate::{Criteria, Run};
+
+    const MAILS: &[&str] = &[
+        "Message-ID: <1@x>\nDate: Mon, 1 Jan 2024 10:00:00 +0000\n\nFirst\n",
+        "Message-ID: <2@x>\nDate: Tue, 2 Jan 2024 10:00:00 +0000\n\nSecond\n",
+    ];
+
+    #[test]
+    fn unreadable_left_out() {
+        let dir = TempDir::new("sink-unreadable");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let path = dir.path().join("archive.mbox");
+        let mut archive = Archive::open(&path, Compression::None, None).unwrap();
+        let journal = Journal::for_archive(&path);
+        let opts = Options {
+            journal: true,
+            verify: true,
+            ..Options::default()
+        };
+        fs::remove_file(&mails[0].path).unwrap();
+        let deleted = archive_batch(&mails, &mut archive, Some(&journal), &opts).unwrap();
+        assert_eq!(vec![false, true], deleted);
+        assert!(!mails[1].path.exists());
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("First"));
+        assert!(content.contains("Second"));
+        assert!(journal.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_verification() {
+        let dir = TempDir::new("sink-verification");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let path = dir.path().join("archive.mbox");
+        fs::write(&path, "From x Thu Jan  1 00:00:00 1970\n\nOld\n\n").unwrap();
+        let mut archive = Archive::open(&path, Compression::None, None).unwrap();
+        let journal = Journal::for_archive(&path);
+        let opts = Options {
+            journal: true,
+            verify: true,
+            ..Options::default()
+        };
+        let written = write_batch(&mails, &mut archive, Some(&journal), &opts).unwrap();
+        // Damage the second message on the disk
+        let damaged = fs::read_to_string(&path)
+            .unwrap()
+            .replace("Second", "Secnod");
+        fs::write(&path, damaged).unwrap();
+        let deleted = settle_batch(&mails, written, &mut archive, Some(&journal), &opts).unwrap();
+        assert_eq!(vec![false, false], deleted);
+        assert!(mails.iter().all(|mail| mail.path.exists()));
+        assert_eq!(
+            "From x Thu Jan  1 00:00:00 1970\n\nOld\n\n",
+            fs::read_to_string(&path).unwrap()
+        );
+        assert!(journal.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn single_stream_batches() {
+        let dir = TempDir::new("sink-single-stream");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let path = dir.path().join("archive.mbox.gz");
+        let opts = Options {
+            single_stream: true,
+            batch_size: 1,
+            confirm: true,
+            ..Options::default()
+        };
+        let mut sink = Mbox::new(&opts, &path).unwrap();
+        let mut archived = Vec::new();
+        let mut done = |mail: MailInfo, ok| archived.push((mail.id, ok));
+        let first = mails[0].path.clone();
+        let mut mails = mails.into_iter();
+        sink.put(mails.next().unwrap(), &mut done).unwrap();
+        // Deleted with its batch, not at the end
+        assert!(!first.exists());
+        sink.put(mails.next().unwrap(), &mut done).unwrap();
+        sink.finish(&mut done).unwrap();
+        assert_eq!(
+            vec![("0.test".to_owned(), true), ("1.test".to_owned(), true)],
+            archived
+        );
+        // Only a single gzip stream
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert!(content.contains("First") && content.contains("Second"));
+        assert!(!dir.path().join("archive.mbox.gz.decay-tmp").exists());
+    }
+
+    #[test]
+    fn recovered_on_start() {
+        let dir = TempDir::new("sink-recover");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let archives = dir.path().join("archives");
+        let archive = archives.join("2024.mbox");
+        fs::create_dir_all(&archives).unwrap();
+        fs::write(
+            &archive,
+            "From x Thu Jan  1 00:00:00 1970\n\nOld\n\nFrom broken",
+        )
+        .unwrap();
+        let journal = Journal::for_archive(&archive);
+        journal.begin(38).unwrap();
+        fs::write(archives.join("2023.mbox.decay-tmp"), "").unwrap();
+        let template = archives.join("%Y.mbox");
+        let mut opts = Options {
+            journal: true,
+            ..Options::default()
+        };
+
+        // Just reported in a dry run
+        Mbox::new(&opts, &template).unwrap();
+        assert!(journal.load().unwrap().is_some());
+
+        opts.confirm = true;
+        Mbox::new(&opts, &template).unwrap();
+        assert!(journal.load().unwrap().is_none());
+        assert_eq!(
+            "From x Thu Jan  1 00:00:00 1970\n\nOld\n\n",
+            fs::read_to_string(&archive).unwrap()
+        );
+        assert!(!archives.join("2023.mbox.decay-tmp").exists());
+        assert!(mails.iter().all(|mail| mail.path.exists()));
+    }
+
+    #[test]
+    fn pool_bounded() {
+        let dir = TempDir::new("sink-pool");
+        let count = OPEN_ARCHIVES + 2;
+        let texts = (0..count)
+            .map(|i| format!("Date: Mon, 1 Jan 2024 10:00:00 +0000\n\nBody {}\n", i))
+            .collect::<Vec<_>>();
+        let texts = texts.iter().map(String::as_str).collect::<Vec<_>>();
+        let mails = testdir::mails(&dir.maildir("box", &texts));
+        let opts = Options {
+            confirm: true,
+            ..Options::default()
+        };
+        let mut sink = Mbox::new(&opts, &dir.path().join("{folder}.mbox")).unwrap();
+        let mut archived = 0;
+        let mut done = |_: MailInfo, ok| archived += ok as usize;
+        for (i, mut mail) in mails.into_iter().enumerate() {
+            mail.folder = format!("f{}", i);
+            sink.put(mail, &mut done).unwrap();
+            assert!(sink.targets.len() <= OPEN_ARCHIVES);
+        }
+        // The first ones were closed and complete already
+        assert!(!sink.targets.contains_key(&dir.path().join("f0.mbox")));
+        let first = fs::read_to_string(dir.path().join("f0.mbox")).unwrap();
+        assert!(first.contains("Body 0\n"));
+        sink.finish(&mut done).unwrap();
+        assert_eq!(count, archived);
+    }
+
+    #[test]
+    fn unsynced_deletions() {
+        let dir = TempDir::new("sink-unsynced");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let opts = Options {
+            confirm: true,
+            ..Options::default()
+        };
+        // Takes the writes into the buffer, fails to flush them
+        let mut sink = Mbox::new(&opts, Path::new("/dev/full")).unwrap();
+        let mut archived = 0;
+        let mut done = |_: MailInfo, ok| archived += ok as usize;
+        for mail in mails {
+            sink.put(mail, &mut done).unwrap();
+        }
+        let e = sink.finish(&mut done).unwrap_err();
+        assert_eq!(2, archived);
+        assert_eq!(
+            "2 messages were already deleted and may be missing from /dev/full",
+            e.to_string()
+        );
+    }
+
+    /// Holds all the messages until the end, like a batching sink does.
+    struct Held<'a> {
+        held: Vec<MailInfo>,
+        ids: &'a mut Vec<String>,
+    }
+
+    impl Sink for Held<'_> {
+        fn put(&mut self, mail: MailInfo, _done: &mut Done) -> Result<(), Error> {
+            self.held.push(mail);
+            Ok(())
+        }
+
+        fn finish(&mut self, done: &mut Done) -> Result<(), Error> {
+            for mail in self.held.drain(..) {
+                self.ids.push(mail.id.clone());
+                done(mail, true);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_sink() {
+        let dir = TempDir::new("sink-custom");
+        let maildir = dir.maildir("box", MAILS);
+        let opts = Options {
+            maildir: vec![maildir.clone()],
+            select: Some("seen".parse().unwrap()),
+            report: report::Format::Json,
+            confirm: true,
+            ..Options::default()
+        };
+        let mut ids = Vec::new();
+        let sink = Held {
+            held: Vec::new(),
+            ids: &mut ids,
+        };
+        let mut run = Run::with_sink(&opts, Criteria::new(&opts), Box::new(sink)).unwrap();
+        let folder = Folder {
+            name: "box".to_owned(),
+            path: maildir,
+        };
+        run.process(&folder).unwrap();
+        let report = run.finish();
+        assert!(report.error.is_none());
+        assert_eq!(2, report.total().archived);
+        // The reason is the one the message was archived for
+        let decisions = report.decisions.unwrap();
+        assert!(decisions
+            .iter()
+            .all(|d| d.action == Action::Archived && d.reason == Reason::Selected));
+        ids.sort();
+        assert_eq!(vec!["0.test", "1.test"], ids);
+    }
+
+    #[test]
+    fn mailbox_quota() {
+        let dir = TempDir::new("sink-quota");
+        let old = "Date: Sun, 31 Dec 2023 10:00:00 +0000\n\nOld\n";
+        let folders = [
+            Folder {
+                name: "box".to_owned(),
+                path: dir.maildir("box", MAILS),
+            },
+            // The oldest message of the mailbox and a copy of the second one
+            Folder {
+                name: "sub".to_owned(),
+                path: dir.maildir("sub", &[old, MAILS[1]]),
+            },
+        ];
+        let total = (MAILS[0].len() + 2 * MAILS[1].len() + old.len()) as u64;
+        let opts = Options {
+            select: Some("seen".parse().unwrap()),
+            report: report::Format::Json,
+            dedup: Some(dedup::Mode::Remove),
+            // Fits once the copy and the oldest message are gone
+            max_size: Some(total - MAILS[1].len() as u64 - 1),
+            ..Options::default()
+        };
+        let mut ids = Vec::new();
+        let sink = Held {
+            held: Vec::new(),
+            ids: &mut ids,
+        };
+        let mut run = Run::with_sink(&opts, Criteria::new(&opts), Box::new(sink)).unwrap();
+        run.process_mailbox(&folders).unwrap();
+        let report = run.finish();
+        assert_eq!((MAILS[1].len() + old.len()) as u64, report.total().freed);
+        let mut decisions = report
+            .decisions
+            .unwrap()
+            .into_iter()
+            .map(|d| {
+                (
+                    d.folder,
+                    d.path.file_name().unwrap().to_owned(),
+                    d.action,
+                    d.reason,
+                )
+            })
+            .collect::<Vec<_>>();
+        decisions.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        let expected = [
+            ("box", "0.test:2,S", Action::Kept, Reason::WithinQuota),
+            ("box", "1.test:2,S", Action::Kept, Reason::WithinQuota),
+            ("sub", "0.test:2,S", Action::WouldArchive, Reason::Selected),
+            (
+                "sub",
+                "1.test:2,S",
+                Action::WouldDeduplicate,
+                Reason::Duplicate,
+            ),
+        ]
+        .map(|(folder, file, action, reason)| (folder.to_owned(), file.into(), action, reason));
+        assert_eq!(expected.to_vec(), decisions);
+    }
+
+    #[test]
+    fn duplicates_trashed() {
+        let dir = TempDir::new("sink-duplicates");
+        let maildir = dir.maildir("box", &[MAILS[0], MAILS[0], MAILS[0]]);
+        let flagged = maildir.join("cur/2.test:2,FS");
+        fs::rename(maildir.join("cur/2.test:2,S"), &flagged).unwrap();
+        let trash = dir.path().join("trash");
+        crate::deliver::prepare(&trash).unwrap();
+        let opts = Options {
+            maildir: vec![maildir.clone()],
+            select: Some("not seen".parse().unwrap()),
+            archive_eml: Some(dir.path().join("eml")),
+            dedup: Some(dedup::Mode::Remove),
+            trash: Some(trash.clone()),
+            confirm: true,
+            ..Options::default()
+        };
+        opts.check().unwrap();
+        let mut ids = Vec::new();
+        let sink = Held {
+            held: Vec::new(),
+            ids: &mut ids,
+        };
+        let mut run = Run::with_sink(&opts, Criteria::new(&opts), Box::new(sink)).unwrap();
+        let folder = Folder {
+            name: "box".to_owned(),
+            path: maildir.clone(),
+        };
+        run.process(&folder).unwrap();
+        let report = run.finish();
+        assert_eq!(1, report.total().duplicates);
+        assert!(ids.is_empty());
+        // One of the plain copies went to the trash, the flagged one stays
+        assert_eq!(2, testdir::mails(&maildir).len());
+        assert!(flagged.exists());
+        assert_eq!(1, testdir::mails(&trash).len());
+    }
+
+    #[test]
+    fn eml_roundtrip() {
+        let dir = TempDir::new("sink-eml");
+        let mails = testdir::mails(&dir.maildir("box", MAILS));
+        let opts = Options {
+            confirm: true,
+            ..Options::default()
+        };
+        let mut sink = Eml::new(&opts, &dir.path().join("eml/{folder}")).unwrap();
+        let mut archived = Vec::new();
+        let mut done = |mail: MailInfo, ok| archived.push((mail.id, ok));
+        for mail in mails {
+            sink.put(mail, &mut done).unwrap();
+        }
+        sink.finish(&mut done).unwrap();
+        assert_eq!(
+            vec![("0.test".to_owned(), true), ("1.test".to_owned(), true)],
+            archived
+        );
+        for (i, mail) in MAILS.iter().enumerate() {
+            let saved = dir.path().join(format!("eml/box/{}.test.eml", i));
+            assert_eq!(*mail, fs::read_to_string(saved).unwrap());
+        }
+        assert!(testdir::mails(&dir.path().join("box")).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+//! Archive paths with placeholders, expanded for each message.
+//!
+//! The placeholders are strftime-like `%Y` (year), `%y` (two digit year), `%m` (month), `%d`
+//! (day), `%q` (quarter) and `%%` (a literal `%`), all taken from the date of the message, plus
+//! `{folder}` for the name of the folder the message comes from. Any other `%` is kept as it is,
+//! so paths that happen to contain one work as before.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+use crate::date::Civil;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(Vec<u8>),
+    Year,
+    ShortYear,
+    Month,
+    Day,
+    Quarter,
+    Folder,
+}
+
+/// A parsed archive path template.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses the path, looking for the placeholders.
+    pub fn parse(path: &Path) -> Self {
+        const FOLDER: &[u8] = b"{folder}";
+        let mut parts = Vec::new();
+        let mut literal = Vec::new();
+        let mut rest = path.as_os_str().as_bytes();
+        while !rest.is_empty() {
+            let part = if rest.starts_with(FOLDER) {
+                rest = &rest[FOLDER.len()..];
+                Part::Folder
+            } else if rest[0] == b'%' {
+                let part = match rest.get(1) {
+                    Some(b'Y') => Part::Year,
+                    Some(b'y') => Part::ShortYear,
+                    Some(b'm') => Part::Month,
+                    Some(b'd') => Part::Day,
+                    Some(b'q') => Part::Quarter,
+                    Some(b'%') => Part::Literal(b"%".to_vec()),
+                    _ => {
+                        literal.push(b'%');
+                        rest = &rest[1..];
+                        continue;
+                    }
+                };
+                rest = &rest[2..];
+                part
+            } else {
+                literal.push(rest[0]);
+                rest = &rest[1..];
+                continue;
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.split_off(0)));
+            }
+            parts.push(part);
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Self { parts }
+    }
+
+    /// Does the path contain any placeholders?
+    ///
+    /// If not, every message goes to the same archive.
+    pub fn is_templated(&self) -> bool {
+        self.parts.iter().any(|p| !matches!(p, Part::Literal(_)))
+    }
+
+    /// Finds the existing files the template could have expanded to, with the `suffix` appended.
+    ///
+    /// Each placeholder matches any non-empty part of a file name.
+    pub fn existing(&self, suffix: &str) -> Result<Vec<PathBuf>, Error> {
+        // The parts of each path component, the placeholders never contain a slash
+        let mut components = vec![Vec::new()];
+        for part in self.parts.iter().cloned() {
+            match part {
+                Part::Literal(lit) => {
+                    for (i, piece) in lit.split(|&b| b == b'/').enumerate() {
+                        if i > 0 {
+                            components.push(Vec::new());
+                        }
+                        if !piece.is_empty() {
+                            components
+                                .last_mut()
+                                .unwrap()
+                                .push(Part::Literal(piece.to_vec()));
+                        }
+                    }
+                }
+                part => components.last_mut().unwrap().push(part),
+            }
+        }
+        components
+            .last_mut()
+            .unwrap()
+            .push(Part::Literal(suffix.as_bytes().to_vec()));
+
+        let absolute =
+            matches!(self.parts.first(), Some(Part::Literal(lit)) if lit.starts_with(b"/"));
+        let mut found = vec![PathBuf::from(if absolute { "/" } else { "" })];
+        let count = components.len();
+        for (i, component) in components.into_iter().enumerate() {
+            if component.is_empty() {
+                continue;
+            }
+            let last = i + 1 == count;
+            let mut next = Vec::new();
+            for dir in found {
+                if component.iter().all(|p| matches!(p, Part::Literal(_))) {
+                    let name = component.iter().fold(Vec::new(), |mut name, part| {
+                        if let Part::Literal(lit) = part {
+                            name.extend_from_slice(lit);
+                        }
+                        name
+                    });
+                    let path = dir.join(OsStr::from_bytes(&name));
+                    if !last || path.exists() {
+                        next.push(path);
+                    }
+                    continue;
+                }
+                let listed = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    &dir
+                };
+                let entries = match fs::read_dir(listed) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        return Err(
+                            Error::from(e).context(format!("Failed to list {}", listed.display()))
+                        )
+                    }
+                };
+                for entry in entries {
+                    let entry =
+                        entry.with_context(|| format!("Failed to list {}", listed.display()))?;
+                    let name = entry.file_name();
+                    if matches(&component, name.as_bytes()) {
+                        next.push(dir.join(name));
+                    }
+                }
+            }
+            found = next;
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// Expands the template for a message with the given date (unix timestamp) from the given
+    /// folder.
+    pub fn expand(&self, date: i64, folder: &str) -> PathBuf {
+        let civil = Civil::from_timestamp(date);
+        let mut path = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(lit) => path.extend_from_slice(lit),
+                Part::Year => path.extend(format!("{:04}", civil.year).bytes()),
+                Part::ShortYear => {
+                    path.extend(format!("{:02}", civil.year.rem_euclid(100)).bytes())
+                }
+                Part::Month => path.extend(format!("{:02}", civil.month).bytes()),
+                Part::Day => path.extend(format!("{:02}", civil.day).bytes()),
+                Part::Quarter => path.extend(format!("{}", civil.quarter()).bytes()),
+                Part::Folder => path.extend(folder.replace('/', ".").bytes()),
+            }
+        }
+        PathBuf::from(OsString::from_vec(path))
+    }
+}
+
+/// Does the file name match the parts of a path component?
+fn matches(parts: &[Part], name: &[u8]) -> bool {
+    match parts.split_first() {
+        None => name.is_empty(),
+        Some((Part::Literal(lit), rest)) => {
+            name.starts_with(lit) && matches(rest, &name[lit.len()..])
+        }
+        Some((_, rest)) => (1..=name.len()).any(|len| matches(rest, &name[len..])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    // 2024-02-29 12:34:56
+    const DATE: i64 = 1_709_210_096;
+
+    #[test]
+    fn plain() {
+        let template = Template::parse(Path::new("/tmp/archive.gz"));
+        assert!(!template.is_templated());
+        assert_eq!(Path::new("/tmp/archive.gz"), template.expand(DATE, "INBOX"));
+    }
+
+    #[test]
+    fn placeholders() {
+        let template = Template::parse(Path::new("/tmp/{folder}_%Y-%m-%d_q%q_%y%%.gz"));
+        assert!(template.is_templated());
+        assert_eq!(
+            Path::new("/tmp/.Lists.foo_2024-02-29_q1_24%.gz"),
+            template.expand(DATE, ".Lists.foo")
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        for path in &["archive_%x", "archive_%", "100%_done/%Y"] {
+            let template = Template::parse(Path::new(path));
+            let expanded = template.expand(DATE, "INBOX");
+            assert_eq!(Path::new(&path.replace("%Y", "2024")), expanded);
+        }
+    }
+
+    #[test]
+    fn existing() {
+        let dir = TempDir::new("template-existing");
+        let archives = dir.path().join("archives");
+        for name in &[
+            "2023/a.mbox.journal",
+            "2024/b.mbox.journal",
+            "2024/b.mbox",
+            "x/c.txt",
+        ] {
+            let path = archives.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let template = Template::parse(&archives.join("%Y/{folder}.mbox"));
+        assert_eq!(
+            vec![
+                archives.join("2023/a.mbox.journal"),
+                archives.join("2024/b.mbox.journal")
+            ],
+            template.existing(".journal").unwrap()
+        );
+        let plain = Template::parse(&archives.join("2024/b.mbox"));
+        assert_eq!(
+            vec![archives.join("2024/b.mbox.journal")],
+            plain.existing(".journal").unwrap()
+        );
+        let missing = Template::parse(&dir.path().join("none/%Y.mbox"));
+        assert!(missing.existing(".journal").unwrap().is_empty());
+    }
+}
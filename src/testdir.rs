@@ -0,0 +1,56 @@
+//! Scratch directories for the tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use maildir::Maildir;
+
+use crate::date::Source;
+use crate::MailInfo;
+
+/// A fresh directory under the system temporary one, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory, the `name` has to be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("decay-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Creates a maildir with the messages in `cur`, all seen.
+    pub fn maildir(&self, name: &str, mails: &[&str]) -> PathBuf {
+        let maildir = self.0.join(name);
+        for sub in &["cur", "new", "tmp"] {
+            fs::create_dir_all(maildir.join(sub)).unwrap();
+        }
+        for (i, mail) in mails.iter().enumerate() {
+            let path = maildir.join("cur").join(format!("{}.test:2,S", i));
+            fs::write(path, mail).unwrap();
+        }
+        maildir
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Reads the messages in `cur` of the maildir, ordered by the file names.
+pub fn mails(maildir: &Path) -> Vec<MailInfo> {
+    let mut mails = Maildir::from(maildir.to_owned())
+        .list_cur()
+        .map(|entry| MailInfo::new(&mut entry.unwrap(), "box", &[Source::Header]).unwrap())
+        .collect::<Vec<_>>();
+    mails.sort_by(|a, b| a.path.cmp(&b.path));
+    mails
+}
@@ -0,0 +1,120 @@
+//! Grouping messages into conversations.
+//!
+//! Messages belong to the same thread if they are connected through their `Message-ID`,
+//! `In-Reply-To` and `References` headers, directly or over other messages. The messages in
+//! between don't have to be present, a shared reference is enough.
+
+use std::collections::HashMap;
+
+use crate::select::Message;
+
+/// The message IDs in a header value, without the angle brackets.
+///
+/// A value without any brackets is taken as a single bare ID.
+fn ids(value: &str) -> Vec<&str> {
+    let value = value.trim();
+    if !value.contains('<') {
+        return Some(value)
+            .filter(|id| !id.is_empty())
+            .into_iter()
+            .collect();
+    }
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('>'))
+        .map(|(id, _)| id.trim())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Disjoint sets of nodes.
+#[derive(Default)]
+struct Sets {
+    parent: Vec<usize>,
+}
+
+impl Sets {
+    fn add(&mut self) -> usize {
+        self.parent.push(self.parent.len());
+        self.parent.len() - 1
+    }
+
+    fn find(&mut self, mut node: usize) -> usize {
+        while self.parent[node] != node {
+            self.parent[node] = self.parent[self.parent[node]];
+            node = self.parent[node];
+        }
+        node
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent[a] = b;
+    }
+}
+
+/// Assigns each message the number of its thread.
+///
+/// The result goes in the same order as the messages, the ones with the same number belong to the
+/// same thread. The numbers themselves mean nothing.
+pub fn group<M: Message>(mails: &[M]) -> Vec<usize> {
+    let mut sets = Sets::default();
+    let mut nodes = HashMap::new();
+    let mut own = Vec::with_capacity(mails.len());
+    for mail in mails {
+        // Each message has a node of its own, so ones without any IDs stay alone
+        let node = sets.add();
+        for header in &["Message-ID", "In-Reply-To", "References"] {
+            for value in mail.header(header) {
+                for id in ids(value) {
+                    let other = *nodes.entry(id).or_insert_with(|| sets.add());
+                    sets.join(node, other);
+                }
+            }
+        }
+        own.push(node);
+    }
+    own.into_iter().map(|node| sets.find(node)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::select::mock::Mail;
+
+    #[test]
+    fn parse_ids() {
+        assert_eq!(ids(" <a@x> "), vec!["a@x"]);
+        assert_eq!(ids("<a@x>\n\t<b@x> <c@x"), vec!["a@x", "b@x"]);
+        assert_eq!(ids("bare@x"), vec!["bare@x"]);
+        assert!(ids("  ").is_empty());
+    }
+
+    #[test]
+    fn threads() {
+        let mails = [
+            Mail::with_headers(vec![("Message-ID", "<root@x>")]),
+            Mail::with_headers(vec![("Message-ID", "<other@x>")]),
+            // The reply in between is missing, the references still connect it
+            Mail::with_headers(vec![
+                ("Message-ID", "<late@x>"),
+                ("In-Reply-To", "<reply@x>"),
+                ("References", "<root@x> <reply@x>"),
+            ]),
+            Mail::with_headers(vec![]),
+            Mail::with_headers(vec![
+                ("message-id", "<sibling@x>"),
+                ("references", "<reply@x>"),
+            ]),
+            Mail::with_headers(vec![("In-Reply-To", "<other@x>")]),
+        ];
+        let threads = group(&mails);
+        assert_eq!(threads[0], threads[2]);
+        assert_eq!(threads[0], threads[4]);
+        assert_eq!(threads[1], threads[5]);
+        assert_ne!(threads[0], threads[1]);
+        assert_ne!(threads[3], threads[0]);
+        assert_ne!(threads[3], threads[1]);
+    }
+}
@@ -0,0 +1,435 @@
+//! Removing messages with a grace period.
+//!
+//! Instead of deleting them right away, removed messages are either moved to a trash maildir or
+//! marked with the maildir `T` (trashed) flag. They are purged by a later run, once they've been
+//! trashed for longer than the grace period.
+//!
+//! The trash maildir keeps an undo log (`decay-undo.log`) with the time and the original location
+//! of each message, so they can be put back. The times of the messages marked by the flag are kept
+//! in `decay-trashed.log` in their maildir. Messages trashed by something else are taken as trashed
+//! when a run first finds them.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Error};
+use log::{error, info};
+
+use crate::deliver;
+
+const UNDO_LOG: &str = "decay-undo.log";
+const TIMES: &str = "decay-trashed.log";
+
+/// The maildir containing the message file (the parent of its `cur` or `new`).
+fn maildir_of(path: &Path) -> Result<&Path, Error> {
+    path.parent()
+        .and_then(Path::parent)
+        .with_context(|| format!("{} is not in a maildir", path.display()))
+}
+
+/// The maildir ID of a message file name (without the flags).
+fn id_of(name: &OsStr) -> &[u8] {
+    let name = name.as_bytes();
+    match name.windows(3).position(|w| w == b":2,") {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+/// Reads a file of lines, an absent one is empty.
+fn read_lines(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(data
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::from(e).context(format!("Failed to read {}", path.display()))),
+    }
+}
+
+/// Replaces the content of the file, through a temporary one.
+fn replace(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)
+        .and_then(|()| fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Appends a line to the file.
+fn append(path: &Path, line: &[u8]) -> Result<(), Error> {
+    ensure!(
+        line.iter().filter(|&&b| b == b'\n').count() == 1,
+        "Can't log file name with newline"
+    );
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut log| log.write_all(line))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn time_line(id: &[u8], time: i64) -> Vec<u8> {
+    let mut line = format!("{}\t", time).into_bytes();
+    line.extend_from_slice(id);
+    line.push(b'\n');
+    line
+}
+
+fn parse_time_line(line: &[u8]) -> Option<(Vec<u8>, i64)> {
+    let tab = line.iter().position(|&b| b == b'\t')?;
+    let time = std::str::from_utf8(&line[..tab]).ok()?.parse().ok()?;
+    Some((line[tab + 1..].to_vec(), time))
+}
+
+/// The times the messages of a maildir were trashed, by their maildir IDs.
+///
+/// Other processes may add to the file meanwhile, so only the changes are written back.
+pub struct Times {
+    path: PathBuf,
+    times: HashMap<Vec<u8>, i64>,
+    /// Looked at during this run.
+    seen: HashSet<Vec<u8>>,
+    added: Vec<(Vec<u8>, i64)>,
+    removed: HashSet<Vec<u8>>,
+}
+
+impl Times {
+    pub fn load(maildir: &Path) -> Result<Self, Error> {
+        let path = maildir.join(TIMES);
+        let times = read_lines(&path)?
+            .iter()
+            .filter_map(|line| parse_time_line(line))
+            .collect();
+        Ok(Self {
+            path,
+            times,
+            seen: HashSet::new(),
+            added: Vec::new(),
+            removed: HashSet::new(),
+        })
+    }
+
+    /// Records the message as trashed at the given time.
+    pub fn record(maildir: &Path, id: &str, time: i64) -> Result<(), Error> {
+        append(&maildir.join(TIMES), &time_line(id.as_bytes(), time))
+    }
+
+    /// When the message was trashed.
+    ///
+    /// A message not known yet is recorded as trashed `now`.
+    pub fn trashed_at(&mut self, id: &[u8], now: i64) -> i64 {
+        self.seen.insert(id.to_vec());
+        match self.times.get(id) {
+            Some(&time) => time,
+            None => {
+                self.times.insert(id.to_vec(), now);
+                self.added.push((id.to_vec(), now));
+                now
+            }
+        }
+    }
+
+    /// Forgets a purged message.
+    pub fn remove(&mut self, id: &[u8]) {
+        self.removed.insert(id.to_vec());
+    }
+
+    /// Writes the changes back.
+    ///
+    /// The messages not looked at are forgotten too, they are no longer trashed (or no longer
+    /// there).
+    pub fn save(self) -> Result<(), Error> {
+        let Self {
+            path,
+            times,
+            seen,
+            added,
+            mut removed,
+        } = self;
+        removed.extend(times.into_keys().filter(|id| !seen.contains(id)));
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        let mut times = read_lines(&path)?
+            .iter()
+            .filter_map(|line| parse_time_line(line))
+            .filter(|(id, _)| !removed.contains(id))
+            .collect::<Vec<_>>();
+        times.extend(added);
+        // The first record of each message wins
+        let mut known = HashSet::new();
+        times.retain(|(id, _)| known.insert(id.clone()));
+        let data = times
+            .iter()
+            .flat_map(|(id, time)| time_line(id, *time))
+            .collect::<Vec<_>>();
+        replace(&path, &data)
+    }
+}
+
+/// Marks the message with the `T` flag, moving it to `cur` if it is in `new`.
+///
+/// The time is recorded in the maildir.
+pub fn flag(path: &Path, id: &str, flags: &str, now: i64) -> Result<(), Error> {
+    let mut flags = flags.chars().chain(Some('T')).collect::<Vec<_>>();
+    flags.sort_unstable();
+    flags.dedup();
+    let flags = flags.into_iter().collect::<String>();
+    let maildir = maildir_of(path)?;
+    Times::record(maildir, id, now)?;
+    deliver::move_to(path, maildir, id, &flags)
+}
+
+/// One line of the undo log.
+struct Record {
+    time: i64,
+    /// File name inside the `cur` of the trash.
+    name: PathBuf,
+    /// Where the message was before.
+    original: PathBuf,
+}
+
+impl Record {
+    fn parse(line: &[u8]) -> Option<Self> {
+        let mut fields = line.splitn(3, |&b| b == b'\t');
+        let time = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+        let name = PathBuf::from(OsStr::from_bytes(fields.next()?));
+        let original = PathBuf::from(OsStr::from_bytes(fields.next()?));
+        Some(Self {
+            time,
+            name,
+            original,
+        })
+    }
+
+    fn format(&self) -> Vec<u8> {
+        let mut line = format!("{}\t", self.time).into_bytes();
+        line.extend_from_slice(self.name.as_os_str().as_bytes());
+        line.push(b'\t');
+        line.extend_from_slice(self.original.as_os_str().as_bytes());
+        line.push(b'\n');
+        line
+    }
+}
+
+/// A trash maildir.
+pub struct Trash {
+    dir: PathBuf,
+}
+
+impl Trash {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.join(UNDO_LOG)
+    }
+
+    /// Moves a message into the trash and records where it came from.
+    pub fn put(&self, path: &Path, id: &str, flags: &str, now: i64) -> Result<(), Error> {
+        // Absolute, so the undo works from anywhere
+        let original = fs::canonicalize(path)
+            .with_context(|| format!("Failed to examine {}", path.display()))?;
+        let record = Record {
+            time: now,
+            name: PathBuf::from(format!("{}:2,{}", id, flags)),
+            original,
+        };
+        let line = record.format();
+        // Checked before the move, so the message doesn't end up in the trash unlogged
+        ensure!(
+            line.iter().filter(|&&b| b == b'\n').count() == 1,
+            "Can't log file name with newline"
+        );
+        deliver::move_to(path, &self.dir, id, flags)?;
+        append(&self.log_path(), &line)
+    }
+
+    fn load(&self) -> Result<Vec<Record>, Error> {
+        let lines = read_lines(&self.log_path())?;
+        Ok(lines
+            .iter()
+            .filter_map(|line| Record::parse(line))
+            .collect())
+    }
+
+    fn store(&self, records: &[Record]) -> Result<(), Error> {
+        let data = records.iter().flat_map(Record::format).collect::<Vec<_>>();
+        replace(&self.log_path(), &data)
+    }
+
+    /// Deletes messages trashed before the given time.
+    ///
+    /// The times come from the undo log, messages missing in it are taken as trashed `now` when
+    /// first seen. Only lists them unless `confirm` is set. Returns how many were (or would be)
+    /// deleted.
+    pub fn purge(&self, before: i64, now: i64, confirm: bool) -> Result<usize, Error> {
+        let logged = self
+            .load()?
+            .into_iter()
+            .map(|r| (id_of(r.name.as_os_str()).to_vec(), r.time))
+            .collect::<HashMap<_, _>>();
+        let mut times = Times::load(&self.dir)?;
+        let mut purged = 0;
+        for sub in &["cur", "new"] {
+            let dir = self.dir.join(sub);
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(Error::from(e).context(format!("Failed to list {}", dir.display())))
+                }
+            };
+            for entry in entries {
+                let path = entry
+                    .with_context(|| format!("Failed to list {}", dir.display()))?
+                    .path();
+                let id = id_of(path.file_name().unwrap_or_default());
+                let trashed = match logged.get(id) {
+                    Some(&time) => time,
+                    None => times.trashed_at(id, now),
+                };
+                if trashed >= before {
+                    continue;
+                }
+                info!("Purge {}", path.display());
+                if confirm {
+                    if let Err(e) = fs::remove_file(&path) {
+                        error!("Failed to purge {}: {}", path.display(), e);
+                        continue;
+                    }
+                    times.remove(id);
+                }
+                purged += 1;
+            }
+        }
+        if confirm {
+            times.save()?;
+        }
+        if confirm && purged > 0 {
+            let mut records = self.load()?;
+            records.retain(|r| self.dir.join("cur").join(&r.name).exists());
+            self.store(&records)?;
+        }
+        Ok(purged)
+    }
+
+    /// Puts the messages trashed at or after the given time back where they came from.
+    ///
+    /// Only lists them unless `confirm` is set. Returns how many were (or would be) put back.
+    pub fn undo(&self, after: Option<i64>, confirm: bool) -> Result<usize, Error> {
+        let mut records = self.load()?;
+        let mut restored = 0;
+        let mut failed = None;
+        records.retain(|record| {
+            if after.is_some_and(|after| record.time < after) {
+                return true;
+            }
+            let path = self.dir.join("cur").join(&record.name);
+            if !path.exists() {
+                // Purged or moved by the user, nothing to put back
+                return false;
+            }
+            let name = record.name.to_string_lossy();
+            let (id, flags) = name.rsplit_once(":2,").unwrap_or((&name, ""));
+            info!("Put back {}", record.original.display());
+            if !confirm {
+                restored += 1;
+                return true;
+            }
+            let moved = maildir_of(&record.original)
+                .and_then(|maildir| deliver::move_to(&path, maildir, id, flags));
+            match moved {
+                Ok(()) => {
+                    restored += 1;
+                    false
+                }
+                Err(e) => {
+                    error!("{:?}", e);
+                    failed = Some(e);
+                    true
+                }
+            }
+        });
+        if confirm {
+            self.store(&records)?;
+        }
+        match failed {
+            Some(e) => Err(e.context("Failed to put back some messages")),
+            None => Ok(restored),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TempDir;
+
+    const MAIL: &str = "Date: Mon, 1 Jan 2024 10:00:00 +0000\n\nBody\n";
+
+    #[test]
+    fn flag_times() {
+        let dir = TempDir::new("trash-flag");
+        let maildir = dir.maildir("box", &[MAIL, MAIL]);
+        flag(&maildir.join("cur/0.test:2,S"), "0.test", "S", 100).unwrap();
+        assert!(maildir.join("cur/0.test:2,ST").exists());
+
+        let mut times = Times::load(&maildir).unwrap();
+        assert_eq!(100, times.trashed_at(b"0.test", 500));
+        // Marked by someone else, first seen now
+        assert_eq!(500, times.trashed_at(b"1.test", 500));
+        // Recorded meanwhile by another run, not lost by saving
+        Times::record(&maildir, "2.test", 300).unwrap();
+        times.remove(b"0.test");
+        times.save().unwrap();
+
+        let mut times = Times::load(&maildir).unwrap();
+        assert_eq!(500, times.trashed_at(b"1.test", 900));
+        assert_eq!(300, times.trashed_at(b"2.test", 900));
+        assert_eq!(900, times.trashed_at(b"0.test", 900));
+        times.save().unwrap();
+
+        // The ones not looked at any more are forgotten
+        let mut times = Times::load(&maildir).unwrap();
+        assert_eq!(500, times.trashed_at(b"1.test", 1000));
+        times.save().unwrap();
+        let mut times = Times::load(&maildir).unwrap();
+        assert_eq!(1000, times.trashed_at(b"2.test", 1000));
+    }
+
+    #[test]
+    fn purge_times() {
+        let dir = TempDir::new("trash-purge");
+        let source = dir.maildir("box", &[MAIL, MAIL]);
+        let trash = Trash::new(&dir.maildir("trash", &[]));
+        trash
+            .put(&source.join("cur/0.test:2,S"), "0.test", "S", 100)
+            .unwrap();
+        trash
+            .put(&source.join("cur/1.test:2,S"), "1.test", "S", 300)
+            .unwrap();
+        // Dropped into the trash by something else
+        fs::write(dir.path().join("trash/cur/2.test:2,S"), MAIL).unwrap();
+
+        assert_eq!(1, trash.purge(200, 400, false).unwrap());
+        assert_eq!(1, trash.purge(200, 400, true).unwrap());
+        assert!(!dir.path().join("trash/cur/0.test:2,S").exists());
+        // The unknown one counts from the first time it was found (at 400)
+        assert_eq!(1, trash.purge(350, 600, true).unwrap());
+        assert!(dir.path().join("trash/cur/2.test:2,S").exists());
+        assert_eq!(1, trash.purge(500, 600, true).unwrap());
+        assert_eq!(0, trash.undo(None, false).unwrap());
+    }
+}
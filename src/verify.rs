@@ -0,0 +1,162 @@
+//! Checking that the archive really contains what was written into it.
+//!
+//! The messages read back from the archive are compared to the messages as they were before
+//! turning them into the mbox format, so mistakes of the mbox writer are caught too. What the
+//! format changes on purpose (the `Status`, `X-Status` and `Content-Length` headers, the
+//! terminating newline and the `>From ` quoting lost in mboxo) is left out of the comparison.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use anyhow::{ensure, Context, Error};
+use mailparse::{parse_headers, MailHeaderMap};
+
+use crate::mbox::{self, Format};
+
+/// What identifies a message in the archive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fingerprint {
+    message_id: String,
+    date: String,
+    header_hash: u64,
+    body_len: usize,
+    body_hash: u64,
+}
+
+impl Fingerprint {
+    /// Computes the fingerprint of a message, either the raw one or one read from the archive.
+    pub fn of(raw: &[u8], format: Format) -> Result<Self, Error> {
+        let (header, body) = mbox::split(raw);
+        let (headers, _) = parse_headers(header).context("Can't parse mail")?;
+
+        let mut hasher = DefaultHasher::new();
+        let mut skipping = false;
+        for (i, line) in mbox::lines(header).enumerate() {
+            let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+            if !continuation {
+                skipping = (i == 0 && line.starts_with(b"From "))
+                    || mbox::is_field(line, "Status")
+                    || mbox::is_field(line, "X-Status")
+                    || (format.content_length() && mbox::is_field(line, "Content-Length"));
+            }
+            if !skipping {
+                hasher.write(line.strip_suffix(b"\n").unwrap_or(line));
+                hasher.write_u8(b'\n');
+            }
+        }
+        let header_hash = hasher.finish();
+
+        let lossy = matches!(format, Format::Mboxo | Format::Mboxcl);
+        let mut hasher = DefaultHasher::new();
+        let mut body_len = 0;
+        for line in mbox::lines(body) {
+            let line = match line.strip_prefix(b">") {
+                Some(unquoted) if lossy && unquoted.starts_with(b"From ") => unquoted,
+                _ => line,
+            };
+            hasher.write(line);
+            body_len += line.len();
+        }
+        if !format.content_length() && !body.is_empty() && !body.ends_with(b"\n") {
+            hasher.write_u8(b'\n');
+            body_len += 1;
+        }
+
+        Ok(Self {
+            message_id: headers.get_first_value("Message-ID").unwrap_or_default(),
+            date: headers.get_first_value("Date").unwrap_or_default(),
+            header_hash,
+            body_len,
+            body_hash: hasher.finish(),
+        })
+    }
+
+    /// Computes the fingerprints of all the messages in a piece of mbox.
+    pub fn all(data: &[u8], format: Format) -> Result<Vec<Self>, Error> {
+        mbox::read(data, format)
+            .iter()
+            .map(|raw| Self::of(raw, format))
+            .collect()
+    }
+}
+
+/// Checks the messages found in the archive are the expected ones, in the same order.
+pub fn check(found: &[Fingerprint], expected: &[Fingerprint]) -> Result<(), Error> {
+    ensure!(
+        found.len() == expected.len(),
+        "Expected {} messages in the archive, found {}",
+        expected.len(),
+        found.len()
+    );
+    for (found, expected) in found.iter().zip(expected) {
+        ensure!(
+            found == expected,
+            "Message {} ({}) is damaged in the archive",
+            expected.message_id,
+            expected.date
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    const FORMATS: &[Format] = &[
+        Format::Mboxo,
+        Format::Mboxrd,
+        Format::Mboxcl,
+        Format::Mboxcl2,
+    ];
+
+    const MAILS: &[&str] = &[
+        "Message-ID: <1@x>\nDate: Mon, 1 Jan 2024 10:00:00 +0000\n\nBody\n",
+        // Things the writer changes
+        "From someone Mon Jan  1 10:00:00 2024\nMessage-ID: <2@x>\nStatus: O\nX-Status: F\n\
+         Content-Length: 3\n\nFrom here\n>From there\n\nFrom again\nno newline",
+        "Message-ID: <3@x>\r\nSubject: CRLF\r\n\r\nBody\r\n\r\n",
+        "Message-ID: <4@x>\nSubject: headers only",
+    ];
+
+    #[test]
+    fn written_matches() {
+        for &format in FORMATS {
+            let mut data = Vec::new();
+            let mut expected = Vec::new();
+            for mail in MAILS {
+                mbox::write(&mut data, mail.as_bytes(), "FS", UNIX_EPOCH, format).unwrap();
+                expected.push(Fingerprint::of(mail.as_bytes(), format).unwrap());
+            }
+            let found = Fingerprint::all(&data, format).unwrap();
+            check(&found, &expected).unwrap_or_else(|e| panic!("{}: {:?}", format, e));
+        }
+    }
+
+    #[test]
+    fn damage_found() {
+        let format = Format::Mboxrd;
+        let mail = b"Message-ID: <1@x>\nSubject: Hello\n\nBody\n";
+        let expected = vec![Fingerprint::of(mail, format).unwrap()];
+        let damaged: &[&[u8]] = &[
+            b"Message-ID: <1@x>\n\nBody\n",
+            b"Message-ID: <1@x>\nSubject: Hello\n\nBod\n",
+            b"Message-ID: <1@x>\nSubject: Hello\n\n>From Body\n",
+        ];
+        for damaged in damaged {
+            let found = vec![Fingerprint::of(damaged, format).unwrap()];
+            assert!(check(&found, &expected).is_err());
+        }
+        assert!(check(&[], &expected).is_err());
+        // The quoting is lost only in mboxo
+        let quoted = b"Subject: x\n\n>From here\n";
+        let unquoted = b"Subject: x\n\nFrom here\n";
+        for &format in FORMATS {
+            let same = Fingerprint::of(quoted, format).unwrap()
+                == Fingerprint::of(unquoted, format).unwrap();
+            assert_eq!(matches!(format, Format::Mboxo | Format::Mboxcl), same);
+        }
+    }
+}